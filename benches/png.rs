@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use png_files::Png;
+
+const FILES: usize = 100;
+const FILE_LEN: usize = 64 * 1024;
+
+fn chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut h = crc32fast::Hasher::new();
+    h.update(chunk_type);
+    h.update(data);
+
+    let mut chunk = Vec::with_capacity(4 + 4 + data.len() + 4);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&h.finalize().to_be_bytes());
+
+    chunk
+}
+
+// a structurally valid png with a few large IDAT chunks
+// the pixel data is nonsense, but nothing here decodes it
+fn image() -> Vec<u8> {
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&1024u32.to_be_bytes());
+    ihdr.extend_from_slice(&1024u32.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = vec![0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+    png.extend(chunk(b"IHDR", &ihdr));
+    for i in 0..16u8 {
+        png.extend(chunk(b"IDAT", &vec![i; 1024 * 1024]));
+    }
+    png.extend(chunk(b"IEND", &[]));
+
+    png
+}
+
+// somewhat compressible, but not trivially so
+fn file_data(seed: usize) -> Vec<u8> {
+    (0..FILE_LEN)
+        .map(|i| ((i * 31 + seed) % 251) as u8 ^ (i / 64) as u8)
+        .collect()
+}
+
+fn files() -> Vec<(String, Vec<u8>)> {
+    (0..FILES)
+        .map(|i| (format!("file{i}.bin"), file_data(i)))
+        .collect()
+}
+
+fn with_files() -> Vec<u8> {
+    let mut png = Png::new(image()).unwrap();
+    let files = files();
+    png.insert_files(files.iter().map(|(k, d)| (k.as_str(), d.clone())), true)
+        .unwrap();
+
+    png.into_bytes()
+}
+
+fn bench(c: &mut Criterion) {
+    let image = image();
+    let embedded = with_files();
+    let files = files();
+
+    c.bench_function("new", |b| {
+        b.iter_batched(
+            || embedded.clone(),
+            |data| Png::new(black_box(data)).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("insert_files", |b| {
+        b.iter_batched(
+            || (Png::new(image.clone()).unwrap(), files.clone()),
+            |(mut png, files)| {
+                png.insert_files(files.iter().map(|(k, d)| (k.as_str(), d.clone())), true)
+                    .unwrap();
+                png
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let png = Png::new(embedded.clone()).unwrap();
+    c.bench_function("get_file", |b| {
+        b.iter(|| png.get_file(black_box("file50.bin")).unwrap())
+    });
+
+    // the last few keys, so a scan has to walk past every other file. few enough lookups
+    // that a freshly parsed png answers them by scanning, before it builds its key index
+    let last_keys = files[FILES - 3..]
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .collect::<Vec<_>>();
+
+    let mut lookups = c.benchmark_group("contains_file");
+
+    let shared = Arc::<[u8]>::from(embedded.as_slice());
+    lookups.bench_function("scan", |b| {
+        b.iter_batched(
+            || Png::from_shared(shared.clone()).unwrap(),
+            |png| {
+                for key in &last_keys {
+                    assert!(png.contains_file(black_box(key)));
+                }
+                png
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // enough lookups up front that png has built its index
+    for (key, _) in &files {
+        assert!(png.contains_file(key));
+    }
+    lookups.bench_function("indexed", |b| {
+        b.iter(|| {
+            for key in &last_keys {
+                assert!(png.contains_file(black_box(key)));
+            }
+        })
+    });
+
+    lookups.finish();
+
+    c.bench_function("into_bytes", |b| {
+        b.iter_batched(
+            || Png::new(embedded.clone()).unwrap(),
+            |png| png.into_bytes(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("compact_to_bytes", |b| {
+        b.iter_batched(
+            || Png::new(embedded.clone()).unwrap(),
+            |png| png.compact_to_bytes(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
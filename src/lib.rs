@@ -0,0 +1,81 @@
+mod png;
+
+use std::{borrow::Cow, io, path::PathBuf};
+
+use bincode::error::{DecodeError, EncodeError};
+use flate2::{CompressError, DecompressError};
+
+// the compression level taken by projected_size_with
+pub use flate2::Compression;
+
+pub use self::png::{
+    Background, ChunkTypeValidation, CompressionMethod, CrcPolicy, CrcWarning, Decoder,
+    DeflateOptions, DeflateStrategy, EditGuard, FileDiff, FileInfo, FilePosition, FileSize,
+    FileStats, IndexedPng, ModificationTime, Overhead, PhysUnit, PhysicalDimensions, Png,
+    StructureWarning, UnknownChunkPolicy, DEFAULT_MAX_DECOMPRESSED, JNG_SIGNATURE, MNG_SIGNATURE,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PngFilesError {
+    #[error("{0}")]
+    Msg(Cow<'static, str>),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0:?}")]
+    Encode(#[from] EncodeError),
+    #[error("{0:?}")]
+    Decode(#[from] DecodeError),
+    #[error("{0:?}")]
+    Compress(#[from] CompressError),
+    #[error("{0:?}")]
+    Decompress(#[from] DecompressError),
+    #[error("Decompressed data exceeds limit of {0} bytes")]
+    DecompressLimit(usize),
+    #[error("Embedded file has {0} unexpected bytes after its data")]
+    TrailingBytes(usize),
+    #[error(
+        "Failed to decode embedded file at chunk index {index}{}: {source:?}",
+        offset.map(|o| format!(" (byte offset {o})")).unwrap_or_default()
+    )]
+    FileDecode {
+        index: usize,
+        // where the chunk starts in the parsed input, none for chunks added since
+        offset: Option<usize>,
+        source: DecodeError,
+    },
+    #[error("Failed to {action} '{}': {source}", path.display())]
+    PathIo {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+impl PngFilesError {
+    /// Attach the path that was being read or written to an io error
+    /// Any other error is returned unchanged
+    pub fn with_path(self, action: &'static str, path: impl Into<PathBuf>) -> Self {
+        match self {
+            Self::Io(source) => Self::PathIo {
+                action,
+                path: path.into(),
+                source,
+            },
+
+            e => e,
+        }
+    }
+    // attach which chunk a file failed to decode from to a bincode error
+    // any other error is returned unchanged
+    fn with_chunk(self, index: usize, offset: Option<usize>) -> Self {
+        match self {
+            Self::Decode(source) => Self::FileDecode {
+                index,
+                offset,
+                source,
+            },
+
+            e => e,
+        }
+    }
+}
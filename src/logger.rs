@@ -0,0 +1,46 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Only errors
+    Quiet,
+    /// Summaries
+    Normal,
+    /// Every file processed
+    Verbose,
+}
+
+/// Minimal logger for the cli
+/// Everything goes to stderr so stdout stays free for data
+pub struct Logger {
+    level: Level,
+}
+
+impl Logger {
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+
+    pub fn info(&self, msg: impl Display) {
+        self.log(Level::Normal, msg);
+    }
+
+    pub fn verbose(&self, msg: impl Display) {
+        self.log(Level::Verbose, msg);
+    }
+
+    fn log(&self, level: Level, msg: impl Display) {
+        if self.level >= level {
+            eprintln!("{msg}");
+        }
+    }
+}
+
+/// Compressed size as a percentage of the original size
+pub fn ratio(compressed: usize, original: usize) -> f64 {
+    if original == 0 {
+        100.0
+    } else {
+        compressed as f64 / original as f64 * 100.0
+    }
+}
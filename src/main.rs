@@ -1,88 +1,697 @@
-mod png;
+mod logger;
 
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fs,
-    io::{self},
-    path::PathBuf,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
 };
 
-use bincode::error::{DecodeError, EncodeError};
-use clap::Parser;
-use flate2::{CompressError, DecompressError};
+use base64::Engine;
+use clap::{ArgGroup, Parser, ValueEnum};
+use png_files::{CompressionMethod, Png, PngFilesError};
 
-use self::png::Png;
+use self::logger::{ratio, Level, Logger};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(group(
+    // exactly one mode
+    ArgGroup::new("mode")
+        .required(true)
+        .args(["decode", "encode", "remove", "check", "compare", "stats"]),
+))]
 struct Args {
     /// Decode files from PNG
-    #[arg(short, long, required = true)]
-    #[arg(conflicts_with_all = ["encode", "remove"])]
+    #[arg(short, long)]
     decode: bool,
 
     /// Encode files into PNG
-    #[arg(short, long, required = true)]
-    #[arg(conflicts_with_all = ["decode", "remove"])]
+    #[arg(short, long)]
     encode: bool,
 
     // Remove files from PNG
-    #[arg(short, long, required = true)]
-    #[arg(conflicts_with_all = ["encode", "decode"])]
+    #[arg(short, long)]
     remove: bool,
 
+    /// Check that every embedded file decodes, failing if any doesn't
+    #[arg(long)]
+    check: bool,
+
+    /// Print every embedded file's compressed and decompressed size, worst ratio first
+    #[arg(long)]
+    stats: bool,
+
+    /// Compare the embedded files of two PNGs, failing if they differ
+    /// Prints added (+), removed (-) and changed (~) keys
+    #[arg(long, num_args = 2, value_names = ["A", "B"])]
+    compare: Vec<PathBuf>,
+
     /// The input file path
-    #[arg(short, long, required = true)]
-    input: PathBuf,
+    /// Can be given several times to apply the same operation to every input
+    #[arg(short, long, required_unless_present = "compare")]
+    input: Vec<PathBuf>,
 
     /// The file path to output to in encode mode
     /// The output directory to decode files to in decode mode
     /// Does nothing in remove mode
+    /// With several inputs, encode mode writes to output/<input name> and decode mode to
+    /// output/<input name without extension>/
     #[arg(short, long, default_value = ".")]
     output: PathBuf,
 
     /// In encode mode, the list of files to encode into output file
+    /// (use path:method to pick compression per file: store, gzip, deflate or deflate0-deflate9)
     /// In decode mode, the list of files to decode from input file
+    /// (use key=dest to write a file to a specific path)
     /// In remove mode, the list of files to remove from input file
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = ["data_b64", "data_hex", "check", "compare", "stats"])]
     files: Vec<PathBuf>,
+
+    /// Base64 encoded data to embed under --key, instead of reading a file
+    #[arg(long, requires = "key", conflicts_with_all = ["data_hex", "decode", "remove", "check", "compare", "stats"])]
+    data_b64: Option<String>,
+
+    /// Hex encoded data to embed under --key, instead of reading a file
+    #[arg(long, requires = "key", conflicts_with_all = ["decode", "remove", "check", "compare", "stats"])]
+    data_hex: Option<String>,
+
+    /// In encode mode, add to an already existing output file instead of overwriting it,
+    /// keeping the files already embedded in it
+    #[arg(long, conflicts_with_all = ["decode", "remove", "check", "compare", "stats"])]
+    update: bool,
+
+    /// In encode mode, embed into a blank 1x1 image if the input isn't a PNG,
+    /// instead of failing
+    #[arg(long, conflicts_with_all = ["decode", "remove", "check", "compare", "stats"])]
+    auto_cover: bool,
+
+    /// What to do when an encoded file's key is already in use
+    #[arg(long, value_enum, default_value_t = OnCollision::Overwrite)]
+    on_collision: OnCollision,
+
+    /// In encode mode, allow several files with the same name, the last one wins
+    #[arg(long, conflicts_with_all = ["decode", "remove", "check", "compare", "stats"])]
+    force: bool,
+
+    /// In encode mode, embed symlinks as links to their target instead of following them
+    /// In decode mode, recreate embedded symlinks (on unix), instead of writing their target
+    /// path out as a regular file
+    #[arg(long, conflicts_with_all = ["remove", "check", "compare", "stats"])]
+    preserve_symlinks: bool,
+
+    /// In decode mode, write the single requested file to stdout instead of to a path
+    #[arg(long, conflicts_with_all = ["encode", "remove", "check", "compare", "stats"])]
+    stdout: bool,
+
+    /// In decode mode, also extract the files embedded in any requested file that is a PNG
+    /// itself, into a directory named after it without its extension, recursively
+    #[arg(long, conflicts_with_all = ["encode", "remove", "check", "compare", "stats", "stdout"])]
+    recursive: bool,
+
+    /// In decode mode, name extracted files after this template, e.g. extracted_{key}
+    /// Path separators in the key are replaced with _ so every file stays in the output
+    #[arg(long, conflicts_with_all = ["encode", "remove", "check", "compare", "stats", "stdout"])]
+    output_template: Option<String>,
+
+    /// In encode and remove mode, also remove text, time and Exif chunks from the image
+    #[arg(long, conflicts_with_all = ["decode", "check", "compare", "stats"])]
+    strip_metadata: bool,
+
+    /// The key to embed --data-b64 or --data-hex data under
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Print nothing but errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print every file processed along with its size and compression ratio
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OnCollision {
+    /// Replace the existing file
+    Overwrite,
+    /// Fail
+    Error,
+    /// Embed under a new key, e.g. `name (1).ext`
+    Suffix,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, PngFilesError> {
+    fs::read(path).map_err(|e| PngFilesError::from(e).with_path("read", path))
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), PngFilesError> {
+    fs::write(path, data).map_err(|e| PngFilesError::from(e).with_path("write", path))
+}
+
+fn read_link(path: &Path) -> Result<String, PngFilesError> {
+    let target =
+        fs::read_link(path).map_err(|e| PngFilesError::from(e).with_path("read link", path))?;
+
+    target.into_os_string().into_string().map_err(|_| {
+        PngFilesError::Msg(Cow::Owned(format!(
+            "Symlink target of {} is not valid UTF-8",
+            path.display()
+        )))
+    })
+}
+
+// recreate an embedded symlink, replacing whatever is at path like `write` does
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path, _log: &Logger) -> Result<(), PngFilesError> {
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path).map_err(|e| PngFilesError::from(e).with_path("remove", path))?;
+    }
+
+    std::os::unix::fs::symlink(target, path)
+        .map_err(|e| PngFilesError::from(e).with_path("create symlink", path))
+}
+
+// creating symlinks needs extra privileges elsewhere, so the target is written out instead
+#[cfg(not(unix))]
+fn symlink(target: &str, path: &Path, log: &Logger) -> Result<(), PngFilesError> {
+    log.info(format!(
+        "symlinks are only supported on unix, writing the target of {} as a file",
+        path.display()
+    ));
+
+    write(path, target.as_bytes())
+}
+
+// first free key of the form `name (n).ext`
+fn unique_key(png: &Png, key: &str) -> String {
+    // a leading dot is part of the name (e.g. `.env`), not an extension
+    let (name, ext) = match key.rfind('.') {
+        Some(idx) if idx > 0 => key.split_at(idx),
+        _ => (key, ""),
+    };
+
+    (1..)
+        .map(|n| format!("{name} ({n}){ext}"))
+        .find(|key| !png.contains_file(key))
+        .unwrap()
+}
+
+// print the difference in embedded files between two images, failing if there is any
+fn compare(a: &Path, b: &Path) -> Result<(), PngFilesError> {
+    let a = Png::new(read(a)?)?;
+    let b = Png::new(read(b)?)?;
+
+    let diff = a.diff(&b);
+
+    for key in &diff.added {
+        println!("+ {key}");
+    }
+
+    for key in &diff.removed {
+        println!("- {key}");
+    }
+
+    for key in &diff.changed {
+        println!("~ {key}");
+    }
+
+    if !diff.is_empty() {
+        Err(PngFilesError::Msg(Cow::Owned(format!(
+            "Images differ: {} added, {} removed, {} changed",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        ))))?;
+    }
+
+    Ok(())
+}
+
+// directory the files embedded in the PNG at path are extracted to, next to it
+fn nested_dir(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(_) => path.with_extension(""),
+        // the image itself is already at path
+        None => path.with_extension("files"),
+    }
+}
+
+// extract everything embedded in a PNG that was itself embedded, returning how many files
+// an image that fails to parse is just an image, so it's skipped rather than an error
+fn extract_nested(data: &[u8], path: &Path, log: &Logger) -> Result<usize, PngFilesError> {
+    let Ok(png) = Png::new(data.to_vec()) else {
+        return Ok(0);
+    };
+
+    let files = png.extract_all_recursive()?;
+
+    // every destination is checked before anything is written, so a bad key doesn't
+    // leave the extraction half done
+    let dests = files
+        .keys()
+        .map(|key| Ok((key, nested_dest(path, key, &files)?)))
+        .collect::<Result<Vec<_>, PngFilesError>>()?;
+
+    for (key, dest) in dests {
+        let data = &files[key];
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| PngFilesError::from(e).with_path("create", parent))?;
+        }
+
+        write(&dest, data)?;
+        log.verbose(format!(
+            "extracted {key} from {} to {} ({} bytes)",
+            path.display(),
+            dest.display(),
+            data.len()
+        ));
+    }
+
+    Ok(files.len())
+}
+
+// where a file extracted from the PNG embedded at path goes
+// nested keys are `outer.png/inner.txt`, every PNG along the way becomes a directory
+//
+// keys come from an untrusted image, so every part must be a plain name that stays
+// inside the directory, never `..`, empty or absolute
+fn nested_dest(
+    path: &Path,
+    key: &str,
+    files: &HashMap<String, Vec<u8>>,
+) -> Result<PathBuf, PngFilesError> {
+    let mut dest = nested_dir(path);
+    let mut prefix = String::new();
+    let mut parts = key.split('/').peekable();
+    while let Some(part) = parts.next() {
+        let mut components = Path::new(part).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Refusing to extract {key} from {}, it would be written outside {}",
+                path.display(),
+                nested_dir(path).display()
+            ))))?;
+        }
+
+        prefix.push_str(part);
+
+        if parts.peek().is_some() && files.get(&prefix).is_some_and(|f| Png::is_png(f)) {
+            dest = nested_dir(&dest.join(part));
+        } else {
+            dest.push(part);
+        }
+
+        prefix.push('/');
+    }
+
+    Ok(dest)
+}
+
+// fail if several files to embed would end up under the same key, listing their sources
+fn duplicate_keys(
+    files: &[(PathBuf, FileCompression)],
+    data_key: Option<&str>,
+) -> Result<(), PngFilesError> {
+    let mut sources = HashMap::<&str, Vec<Cow<str>>>::new();
+
+    for (file, _) in files {
+        // key is the base filename + ext
+        let key = file
+            .file_name()
+            .and_then(|k| k.to_str())
+            .unwrap_or_default();
+        sources.entry(key).or_default().push(file.to_string_lossy());
+    }
+
+    if let Some(key) = data_key {
+        sources.entry(key).or_default().push(Cow::Borrowed("--key"));
+    }
+
+    let mut duplicates = sources
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .map(|(key, sources)| format!("{key} ({})", sources.join(", ")))
+        .collect::<Vec<_>>();
+
+    if !duplicates.is_empty() {
+        duplicates.sort();
+
+        Err(PngFilesError::Msg(Cow::Owned(format!(
+            "Several files would be embedded under the same key, use --force to allow it: {}",
+            duplicates.join("; ")
+        ))))?;
+    }
+
+    Ok(())
+}
+
+// what gets embedded for a file
+enum Embed {
+    Data(Vec<u8>),
+    // target path of a symlink
+    Symlink(String),
+}
+
+// how a single file gets compressed when embedding it
+#[derive(Debug, Clone, Copy)]
+struct FileCompression {
+    method: CompressionMethod,
+    level: u32,
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum PngFilesError {
-    #[error("{0}")]
-    Msg(Cow<'static, str>),
-    #[error("{0}")]
-    Io(#[from] io::Error),
-    #[error("{0:?}")]
-    Encode(#[from] EncodeError),
-    #[error("{0:?}")]
-    Decode(#[from] DecodeError),
-    #[error("{0:?}")]
-    Compress(#[from] CompressError),
-    #[error("{0:?}")]
-    Decompress(#[from] DecompressError),
+impl Default for FileCompression {
+    fn default() -> Self {
+        Self {
+            method: CompressionMethod::Deflate,
+            // same as the library default
+            level: 9,
+        }
+    }
 }
 
-fn main() -> Result<(), PngFilesError> {
+// split `path:method` into the path and how to compress it
+// only the last `:` counts, so paths containing one still work by giving a method
+fn compression_spec(file: PathBuf) -> Result<(PathBuf, FileCompression), PngFilesError> {
+    let Some((path, method)) = file
+        .to_str()
+        .and_then(|f| f.rsplit_once(':'))
+        // a separator after the colon means it's part of the path (e.g. `C:\file`)
+        .filter(|(path, method)| !path.is_empty() && !method.contains(['/', '\\']))
+    else {
+        return Ok((file, FileCompression::default()));
+    };
+
+    let compression = match method {
+        "store" => FileCompression {
+            method: CompressionMethod::Store,
+            ..Default::default()
+        },
+
+        "deflate" => FileCompression::default(),
+
+        "gzip" => FileCompression {
+            method: CompressionMethod::Gzip,
+            ..Default::default()
+        },
+
+        _ => match method.strip_prefix("deflate").map(str::parse) {
+            Some(Ok(level @ 0..=9)) => FileCompression {
+                level,
+                ..Default::default()
+            },
+
+            _ => Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Invalid compression method {method} for {path}; expected store, gzip, deflate or deflate0-deflate9"
+            ))))?,
+        },
+    };
+
+    Ok((PathBuf::from(path), compression))
+}
+
+fn run() -> Result<(), PngFilesError> {
     let args = Args::parse();
 
-    let image = fs::read(&args.input)?;
+    let log = Logger::new(if args.quiet {
+        Level::Quiet
+    } else if args.verbose {
+        Level::Verbose
+    } else {
+        Level::Normal
+    });
 
-    let mut png = Png::new(image)?;
+    if let [a, b] = args.compare.as_slice() {
+        return compare(a, b);
+    }
+
+    // clap guarantees an input outside of compare mode
+    if let [input] = args.input.as_slice() {
+        return process(&args, input, &args.output, &log);
+    }
+
+    if args.stdout {
+        Err(PngFilesError::Msg(Cow::Borrowed(
+            "--stdout takes a single input",
+        )))?;
+    }
+
+    // keep going past failed inputs, so one bad file doesn't stop the whole batch
+    let mut failed = 0;
+    for input in &args.input {
+        match batch_output(&args, input).and_then(|output| process(&args, input, &output, &log)) {
+            Ok(()) => log.info(format!("{}: ok", input.display())),
+            Err(e) => {
+                eprintln!("{}: {e}", input.display());
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(PngFilesError::Msg(Cow::Owned(format!(
+            "{failed} of {} inputs failed",
+            args.input.len()
+        ))))?;
+    }
+
+    log.info(format!("processed all {} inputs", args.input.len()));
+
+    Ok(())
+}
+
+// where an input's output goes when there are several inputs, since they can't all
+// share the one output path
+fn batch_output(args: &Args, input: &Path) -> Result<PathBuf, PngFilesError> {
+    let name = input
+        .file_name()
+        .ok_or(PngFilesError::Msg(Cow::Owned(format!(
+            "{} is not a file",
+            input.display()
+        ))))?;
+
+    if args.encode {
+        let output = args.output.join(name);
+
+        // encode mode leaves its input alone, e.g. with `-o .` and inputs in the current dir
+        if fs::canonicalize(&output).ok() == fs::canonicalize(input).ok() && output.exists() {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Output {} would overwrite the input",
+                output.display()
+            ))))?;
+        }
+
+        Ok(output)
+    } else if args.decode && !args.stdout {
+        // every input gets its own directory, so files with the same key don't collide
+        let output = args
+            .output
+            .join(Path::new(name).file_stem().unwrap_or(name));
+        fs::create_dir_all(&output)
+            .map_err(|e| PngFilesError::from(e).with_path("create", &output))?;
+
+        Ok(output)
+    } else {
+        Ok(args.output.clone())
+    }
+}
+
+// remove identifying chunks if asked to, right before the image is written
+fn strip_metadata(png: &mut Png, args: &Args, log: &Logger) {
+    if args.strip_metadata {
+        let stripped = png.strip_metadata();
+        log.verbose(format!("stripped {stripped} metadata chunks"));
+    }
+}
+
+// run the selected mode on a single input
+fn process(
+    args: &Args,
+    input_path: &Path,
+    output: &Path,
+    log: &Logger,
+) -> Result<(), PngFilesError> {
+    // update mode builds on top of the existing output so its embedded files are kept
+    let input = if args.update && output.is_file() {
+        output
+    } else {
+        input_path
+    };
+
+    let image = read(input)?;
+
+    let mut png = if args.auto_cover && !Png::is_png(&image) {
+        log.verbose(format!(
+            "{} is not a PNG, embedding into a blank image",
+            input.display()
+        ));
+        Png::new_blank(1, 1)?
+    } else {
+        Png::new(image)?
+    };
 
     if args.encode {
-        for file in args.files {
-            let data = std::fs::read(&file)?;
+        let mut embedded = 0;
+        let mut embed = |png: &mut Png, key: &str, data: Embed, compression: FileCompression| {
+            let key = match args.on_collision {
+                OnCollision::Suffix if png.contains_file(key) => Cow::Owned(unique_key(png, key)),
+                _ => Cow::Borrowed(key),
+            };
+
+            let replace = !matches!(args.on_collision, OnCollision::Error);
+            png.set_compression_level(compression.level);
+
+            let result = match data {
+                Embed::Data(data) => {
+                    let len = data.len();
+                    png.insert_file_with_method(&key, data, compression.method, replace)
+                        .map(|()| {
+                            let compressed = png.file_info(&key).map_or(0, |i| i.compressed_len);
+                            log.verbose(format!(
+                                "embedded {key} ({len} bytes, compressed to {:.1}%)",
+                                ratio(compressed, len)
+                            ));
+                            embedded += len;
+                        })
+                }
+
+                Embed::Symlink(target) => png
+                    .insert_symlink(&key, &target, replace)
+                    .map(|()| log.verbose(format!("embedded {key} (symlink to {target})"))),
+            };
+
+            result.map_err(|e| PngFilesError::Msg(Cow::Owned(format!("{key}: {e}"))))?;
+
+            Ok::<_, PngFilesError>(())
+        };
+
+        let files = args
+            .files
+            .iter()
+            .cloned()
+            .map(compression_spec)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // files from different directories can share a name, and all but the last
+        // would be silently lost (suffix mode keeps them all, so it's fine there)
+        if !args.force && !matches!(args.on_collision, OnCollision::Suffix) {
+            let data_key = args
+                .key
+                .as_deref()
+                .filter(|_| args.data_b64.is_some() || args.data_hex.is_some());
+            duplicate_keys(&files, data_key)?;
+        }
+
+        for (file, compression) in files {
+            let data = if args.preserve_symlinks && file.is_symlink() {
+                Embed::Symlink(read_link(&file)?)
+            } else {
+                Embed::Data(read(&file)?)
+            };
+
             let key = file.file_name();
             // key is the base filename + ext
             let key = key.unwrap().to_str().unwrap();
-            png.insert_file(key, data, true)?;
+            embed(&mut png, key, data, compression)?;
+        }
+
+        let data =
+            if let Some(data) = &args.data_b64 {
+                Some(
+                    base64::engine::general_purpose::STANDARD
+                        .decode(data)
+                        .map_err(|e| {
+                            PngFilesError::Msg(Cow::Owned(format!("Invalid base64 data: {e}")))
+                        })?,
+                )
+            } else if let Some(data) = &args.data_hex {
+                Some(hex::decode(data).map_err(|e| {
+                    PngFilesError::Msg(Cow::Owned(format!("Invalid hex data: {e}")))
+                })?)
+            } else {
+                None
+            };
+
+        if let Some(data) = data {
+            // clap guarantees key is set alongside the data
+            let key = args.key.as_deref().unwrap();
+            embed(&mut png, key, Embed::Data(data), FileCompression::default())?;
         }
 
-        std::fs::write(args.output, png.into_bytes())?;
+        strip_metadata(&mut png, args, log);
+
+        let written = png.save(output)?;
+
+        log.info(format!(
+            "embedded {embedded} bytes; wrote {written} bytes to {}",
+            output.display()
+        ));
+    } else if args.decode && args.stdout {
+        let [file] = args.files.as_slice() else {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "--stdout takes exactly one file",
+            )))?
+        };
+
+        let key = file.file_name();
+        // key is the base filename + ext
+        let key = key.unwrap().to_str().unwrap();
+
+        let file = png
+            .get_file(key)
+            .ok_or(PngFilesError::Msg(Cow::Owned(format!(
+                "Key {key} not found in image"
+            ))))?;
+
+        // raw bytes, nothing is added or converted
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&file)?;
+        stdout.flush()?;
+
+        log.verbose(format!("extracted {key} to stdout ({} bytes)", file.len()));
     } else if args.decode {
-        for file in args.files {
+        if let Some(template) = &args.output_template {
+            if !template.contains("{key}") {
+                Err(PngFilesError::Msg(Cow::Owned(format!(
+                    "Output template {template} doesn't contain {{key}}"
+                ))))?;
+            }
+        }
+
+        let mut extracted = 0;
+        for file in &args.files {
+            // `key=dest` writes the file to dest instead of output/key
+            let (file, dest) = match file.to_str().and_then(|f| f.split_once('=')) {
+                Some((file, dest)) => {
+                    if file.is_empty() || dest.is_empty() {
+                        Err(PngFilesError::Msg(Cow::Owned(format!(
+                            "Invalid mapping {file}={dest}; expected key=dest"
+                        ))))?;
+                    }
+
+                    (PathBuf::from(file), Some(PathBuf::from(dest)))
+                }
+
+                None => (file.clone(), None),
+            };
+
             let key = file.file_name();
             // key is the base filename + ext
             let key = key.unwrap().to_str().unwrap();
@@ -93,20 +702,250 @@ fn main() -> Result<(), PngFilesError> {
                     "Key {key} not found in image"
                 ))))?;
 
-            let path = args.output.join(key);
-            std::fs::write(path, file)?;
+            let path = dest.unwrap_or_else(|| match &args.output_template {
+                Some(template) => {
+                    output.join(template.replace("{key}", &key.replace(['/', '\\'], "_")))
+                }
+                None => output.join(key),
+            });
+
+            if args.preserve_symlinks && png.is_symlink(key) {
+                // the target was embedded as utf8, see read_link
+                let target = String::from_utf8_lossy(&file);
+                symlink(&target, &path, log)?;
+
+                log.verbose(format!(
+                    "extracted {key} to {} (symlink to {target})",
+                    path.display()
+                ));
+                extracted += 1;
+                continue;
+            }
+
+            write(&path, &file)?;
+
+            let compressed = png.file_info(key).map_or(0, |i| i.compressed_len);
+            log.verbose(format!(
+                "extracted {key} to {} ({} bytes, compressed to {:.1}%)",
+                path.display(),
+                file.len(),
+                ratio(compressed, file.len())
+            ));
+            extracted += 1;
+
+            if args.recursive && Png::is_png(&file) {
+                extracted += extract_nested(&file, &path, log)?;
+            }
         }
+
+        log.info(format!("extracted {extracted} files"));
     } else if args.remove {
-        for file in args.files {
+        let mut removed = 0;
+        let mut missing = Vec::new();
+        for file in &args.files {
             let key = file.file_name();
             // key is the base filename + ext
             let key = key.unwrap().to_str().unwrap();
 
-            png.remove_file(key);
+            if png.remove_file(key) {
+                log.verbose(format!("removed {key}"));
+                removed += 1;
+            } else {
+                missing.push(key);
+            }
+        }
+
+        // nothing is written, so a typo doesn't leave the image half edited
+        if !missing.is_empty() {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Keys not found in image: {}",
+                missing.join(", ")
+            ))))?;
+        }
+
+        strip_metadata(&mut png, args, log);
+
+        let written = png.save(input_path)?;
+
+        log.info(format!(
+            "removed {removed} files; wrote {written} bytes to {}",
+            input_path.display()
+        ));
+    } else if args.check {
+        let results = png.validate_files();
+        let checked = results.len();
+
+        let mut failed = 0;
+        for (key, result) in results {
+            match result {
+                Ok(()) => log.verbose(format!("{key}: ok")),
+                Err(e) => {
+                    // failures are the point of this mode, so they're shown even when quiet
+                    eprintln!("{key}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "{failed} of {checked} embedded files failed to decode"
+            ))))?;
+        }
+
+        log.info(format!("all {checked} embedded files decoded"));
+    } else if args.stats {
+        let mut stats = png.compression_stats();
+        // files that barely compress are the interesting ones, so they go first
+        stats.sort_by(|a, b| b.ratio.total_cmp(&a.ratio));
+
+        println!("{:>7}  {:>12}  {:>12}  key", "ratio", "compressed", "size");
+        for file in &stats {
+            println!(
+                "{:>6.1}%  {:>12}  {:>12}  {}",
+                file.ratio * 100.0,
+                file.compressed_len,
+                file.decompressed_len,
+                file.key
+            );
         }
 
-        std::fs::write(args.input, png.into_bytes())?;
+        let compressed = stats.iter().map(|f| f.compressed_len).sum::<usize>();
+        let size = stats.iter().map(|f| f.decompressed_len).sum::<usize>();
+        log.info(format!(
+            "{} files, {size} bytes compressed to {compressed} ({:.1}%)",
+            stats.len(),
+            ratio(compressed, size)
+        ));
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh empty directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("png-files-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn png_with(key: &str, data: &[u8]) -> Vec<u8> {
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file(key, data.to_vec(), false).unwrap();
+        png.into_bytes()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nested_extraction_stays_inside_output() {
+        let dir = temp_dir("nested-escape");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+
+        for key in [
+            "../../escaped.txt",
+            "../escaped.txt",
+            "a//b",
+            "/escaped.txt",
+        ] {
+            let inner = png_with(key, b"escaped");
+
+            let result = extract_nested(&inner, &out.join("inner.png"), &Logger::new(Level::Quiet));
+
+            assert!(result.is_err(), "{key} was extracted");
+        }
+
+        assert!(!dir.join("escaped.txt").exists());
+        assert!(!out.join("escaped.txt").exists());
+        assert!(!Path::new("/escaped.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nested_extraction_writes_into_directories() {
+        let dir = temp_dir("nested");
+
+        let mut outer = Png::new_blank(1, 1).unwrap();
+        outer
+            .insert_file("deeper.png", png_with("b.txt", b"b"), false)
+            .unwrap();
+        outer
+            .insert_file("dir/a.txt", b"a".to_vec(), false)
+            .unwrap();
+
+        let count = extract_nested(
+            &outer.into_bytes(),
+            &dir.join("inner.png"),
+            &Logger::new(Level::Quiet),
+        )
+        .unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(fs::read(dir.join("inner/dir/a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(dir.join("inner/deeper/b.txt")).unwrap(), b"b");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_base_names_are_rejected() {
+        let files = ["a/config.json", "b/config.json", "c/other.json"]
+            .map(|f| (PathBuf::from(f), FileCompression::default()));
+
+        let Err(PngFilesError::Msg(msg)) = duplicate_keys(&files, None) else {
+            panic!("duplicate keys were accepted");
+        };
+        assert!(msg.ends_with("config.json (a/config.json, b/config.json)"));
+
+        assert!(duplicate_keys(&files[1..], None).is_ok());
+        assert!(duplicate_keys(&files[1..], Some("other.json")).is_err());
+        assert!(duplicate_keys(&files[1..], Some("new.json")).is_ok());
+    }
+
+    // run the cli on a single input, like main does
+    fn run_args(args: &[&str]) -> Result<(), PngFilesError> {
+        let args = Args::parse_from(["png-files"].iter().chain(args));
+        process(
+            &args,
+            &args.input[0],
+            &args.output,
+            &Logger::new(Level::Quiet),
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_preserved() {
+        let dir = temp_dir("symlinks");
+        let image = dir.join("image.png");
+        let link = dir.join("link");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+
+        fs::write(&image, Png::new_blank(1, 1).unwrap().into_bytes()).unwrap();
+        std::os::unix::fs::symlink("target.txt", &link).unwrap();
+
+        let [image, link, out] = [&image, &link, &out].map(|p| p.to_str().unwrap());
+
+        run_args(&["-e", "-i", image, "-o", image, "--preserve-symlinks", link]).unwrap();
+
+        let png = Png::new(fs::read(image).unwrap()).unwrap();
+        assert!(png.is_symlink("link"));
+        assert_eq!(png.get_file("link").unwrap(), b"target.txt");
+
+        run_args(&["-d", "-i", image, "-o", out, "--preserve-symlinks", "link"]).unwrap();
+        assert_eq!(
+            fs::read_link(Path::new(out).join("link")).unwrap(),
+            Path::new("target.txt")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
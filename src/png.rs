@@ -1,20 +1,48 @@
 use std::{
     borrow::Cow,
-    io::{Cursor, Read, Seek, Write},
+    cell::{Cell, OnceCell},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{BufWriter, Cursor, Read, Seek, Write},
     ops::{Deref, Range},
+    path::Path,
     rc::Rc,
+    sync::Arc,
 };
 
-use bincode::{BorrowDecode, Encode};
+use bincode::{Decode, Encode};
 use byteorder::{BigEndian, ReadBytesExt};
-use flate2::{
-    write::{DeflateDecoder, DeflateEncoder},
-    Compression,
-};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 
 use crate::PngFilesError;
 
-const PNG_HEADER: &[u8] = &[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+mod ancillary;
+#[cfg(feature = "tokio")]
+mod async_io;
+mod deflate;
+#[cfg(feature = "delta")]
+mod delta;
+mod edit;
+mod glob;
+mod index;
+mod reader;
+mod recover;
+mod structure;
+mod zip;
+
+pub use self::ancillary::{Background, ModificationTime, PhysUnit, PhysicalDimensions};
+pub use self::edit::EditGuard;
+pub use self::index::IndexedPng;
+use self::reader::PngReader;
+pub use self::structure::StructureWarning;
+
+const PNG_HEADER: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Signature of MNG (animation) files, which share PNG's chunk layout
+pub const MNG_SIGNATURE: [u8; 8] = [0x8a, 0x4d, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Signature of JNG (JPEG with alpha) files, which share PNG's chunk layout
+pub const JNG_SIGNATURE: [u8; 8] = [0x8b, 0x4a, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
 
 // Custom file chunk type
 //
@@ -27,26 +55,447 @@ const PNG_HEADER: &[u8] = &[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
 // +---- Ancillary bit is 1    (lowercase letter; bit 5 is 1)
 const CHUNK_TYPE: &str = "fiLe";
 
+// Chunk type of a file whose whole encoded `File` is deflated again, key and metadata
+// included, see Png::set_deflate_keys. Same property bits as fiLe
+const PACKED_CHUNK_TYPE: &str = "fiLz";
+
+// Chunk type of every part of a split file but the first, see File
+// Same property bits as fiLe. Readers that don't know it skip it as an unknown ancillary
+// chunk, rather than mistaking it for another file with the same key
+const PART_CHUNK_TYPE: &str = "fiLp";
+
+// Preset deflate dictionary chunk type, shared by any number of files
+//
+// diCt
+// 1101
+// ||||
+// |||+- Safe-to-copy bit is 1
+// ||+-- Reserved bit is 0
+// |+--- Private bit is 1
+// +---- Ancillary bit is 1
+const DICT_CHUNK_TYPE: &str = "diCt";
+
+// longest key accepted when inserting, in bytes, matching the usual PATH_MAX
+const MAX_KEY_LEN: usize = 4096;
+
+// lookups by key that scan the chunks before find_file_index builds an index instead
+const SCANS_BEFORE_INDEX: u32 = 4;
+
+// how deep extract_all_recursive follows PNGs embedded in PNGs, a deflate quine could
+// otherwise nest forever
+const MAX_NESTING: usize = 16;
+
+// upper bound on the decoded size of a file's metadata fields, which are only ever a few
+const META_LIMIT: usize = 64 * 1024;
+
+// marks the metadata after a file's data, followed by META_VERSION, see File
+const META_MAGIC: &[u8; 4] = b"meTa";
+const META_VERSION: u8 = 1;
+
+// Chunk types defined by the PNG spec (and its registered extensions)
+const KNOWN_CHUNK_TYPES: &[&str] = &[
+    "IHDR", "PLTE", "IDAT", "IEND", "tRNS", "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCv",
+    "cLLi", "tEXt", "zTXt", "iTXt", "bKGD", "hIST", "pHYs", "sPLT", "eXIf", "tIME", "acTL", "fcTL",
+    "fdAT",
+];
+
+/// How strictly chunk type names are checked when parsing
+///
+/// The PNG spec requires chunk types to be four ASCII letters, with the third (reserved)
+/// letter uppercase. Our own `fiLe` and `diCt` chunks follow these rules, so they pass
+/// every level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkTypeValidation {
+    /// Reject any chunk type that isn't four ASCII letters with the reserved bit clear
+    #[default]
+    Strict,
+    /// Like `Strict`, but private chunk types (lowercase second letter) may set the
+    /// reserved bit, for experimenting with non-conforming types
+    AllowPrivate,
+    /// Accept any chunk type, as long as it is valid UTF-8
+    NoValidation,
+}
+
+/// What to do with chunks whose stored crc doesn't match their contents when parsing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CrcPolicy {
+    /// Fail on the first mismatch
+    #[default]
+    Strict,
+    /// Keep parsing, reporting every mismatch as a `CrcWarning`
+    WarnOnly,
+    /// Don't check crcs at all
+    Ignore,
+}
+
+/// A chunk whose stored crc doesn't match its contents, see `Png::new_with_crc_policy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrcWarning {
+    /// Index of the chunk, in the order they were parsed
+    pub index: usize,
+    pub chunk_type: String,
+    /// The crc stored in the file
+    pub stored: u32,
+    /// The crc of the chunk's type and data
+    pub computed: u32,
+}
+
+/// What to do with unknown chunks when serializing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownChunkPolicy {
+    /// Keep every chunk as-is
+    #[default]
+    KeepAll,
+    /// Drop unknown ancillary chunks that aren't marked safe-to-copy, like image editors do
+    DropUnsafeAncillary,
+}
+
+/// How much embedded files add on top of the image itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overhead {
+    /// Serialized size of the PNG without any embedded files or their dictionaries
+    pub image_len: usize,
+    /// Serialized size of the PNG including embedded files
+    pub total_len: usize,
+    /// Size added by embedded files, as a percentage of `image_len`
+    pub percent: f64,
+}
+
+/// Information about an embedded file, available without inflating it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Size of the file's data as stored (deflated) in the image
+    pub compressed_len: usize,
+}
+
+/// How well an embedded file compresses, see `Png::compression_stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStats {
+    pub key: String,
+    /// Size of the file's data as stored in the image
+    pub compressed_len: usize,
+    /// Size of the file itself
+    pub decompressed_len: usize,
+    /// `compressed_len / decompressed_len`, 1 for empty files. Near or above 1 means
+    /// deflate isn't worth it and the file may as well be stored
+    pub ratio: f64,
+}
+
+/// Embedded files that differ between two images, see `Png::diff`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    /// Keys only in the other image
+    pub added: Vec<String>,
+    /// Keys only in this image
+    pub removed: Vec<String>,
+    /// Keys in both images whose data differs
+    pub changed: Vec<String>,
+}
+
+impl FileDiff {
+    /// Whether both images hold exactly the same files
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// tEXt chunk written by set_auto_comment, keyword + null separator + text
+// the embedded file count goes between prefix and suffix
+const AUTO_COMMENT_PREFIX: &str = "Comment\0Contains ";
+const AUTO_COMMENT_SUFFIX: &str = " embedded files (png-files)";
+
+/// Which size of an embedded file to go by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSize {
+    /// Size as stored in the image, cheap to get
+    Compressed,
+    /// Size once inflated, which means inflating the file
+    Decompressed,
+}
+
+/// Where a new file chunk is placed among the image's chunks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilePosition {
+    /// Right before IEND
+    #[default]
+    BeforeIend,
+    /// Right after the last IDAT chunk
+    AfterIdat,
+    /// At this chunk index (IHDR is index 0 in a well formed image)
+    AtIndex(usize),
+}
+
+/// How a file's data is stored in the image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMethod {
+    /// Compressed with deflate (files written before this flag existed are always deflated)
+    #[default]
+    Deflate,
+    /// Stored as-is, for data that's already compressed
+    Store,
+    /// Compressed into a complete gzip stream, which `Png::get_file_raw` returns as-is,
+    /// e.g. to serve it with `Content-Encoding: gzip` without compressing it again
+    Gzip,
+    /// Encoded by the caller with their own codec, identified by this id. The data is
+    /// stored as given, and decoded by the decoder registered for the id with
+    /// `Png::register_decoder`
+    Custom(u32),
+}
+
+/// Decodes files stored with `CompressionMethod::Custom`, see `Png::register_decoder`
+///
+/// Called with the data as stored and the most bytes it may decode to (see
+/// `Png::set_max_decompressed`), which it should fail with `PngFilesError::DecompressLimit`
+/// past instead of decoding it all first
+pub type Decoder = dyn Fn(&[u8], usize) -> Result<Vec<u8>, PngFilesError>;
+
+// registered decoders by the id of the custom method they decode
+type Decoders = HashMap<u32, Box<Decoder>>;
+
+/// Deflate strategy, tuning the encoder for specific kinds of data
+/// Strategies only change how data is compressed, any of them inflates the same way
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DeflateStrategy {
+    #[default]
+    Default,
+    /// Only use matches at least 5 bytes long, for data with lots of small variation
+    Filtered,
+    /// Don't look for matches at all, only huffman encode bytes
+    HuffmanOnly,
+    /// Only look for runs of the same byte, for run heavy data like simple bitmaps
+    Rle,
+    /// Only use the fixed huffman codes from the deflate spec
+    Fixed,
+}
+
+/// How a file is deflated when inserted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateOptions {
+    /// 0 - 9, higher is smaller but slower. Levels above 9 are treated as 9
+    pub level: u32,
+    pub strategy: DeflateStrategy,
+}
+
+impl Default for DeflateOptions {
+    fn default() -> Self {
+        Self {
+            level: 9,
+            strategy: DeflateStrategy::Default,
+        }
+    }
+}
+
+/// Default cap on how large a single file may inflate to (1 GiB)
+pub const DEFAULT_MAX_DECOMPRESSED: usize = 1 << 30;
+
 // representing a file object inside the png file
-#[derive(Debug, Encode, BorrowDecode)]
+//
+// stored as the bincode encoded key and data, followed by the metadata if there is any
+// (files from before metadata existed simply end after data). the metadata is META_MAGIC,
+// META_VERSION, then the bincode encoded fields as length prefixed bytes, so junk appended
+// to a chunk is never mistaken for it
+//
+// a file whose stored data is over `Png::set_max_chunk_size` is split into several parts,
+// each a `File` of its own with the same key and a slice of the stored data, in
+// consecutive chunks. parts are numbered 0 up to the part count, both kept in `meta.part`.
+// part 0 is the file as far as everything else is concerned, is a fiLe chunk and has the
+// file's metadata. the other parts are PART_CHUNK_TYPE chunks with only their number, so
+// parsing tells them apart by chunk type alone. a file that isn't split has no part number
+#[derive(Debug)]
 struct File<'a> {
-    key: &'a str,
+    key: Cow<'a, str>,
     data: Cow<'a, [u8]>,
+    meta: FileMeta,
+}
+
+// extra information about how a file was stored
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct FileMeta {
+    // crc of the preset dictionary the data was deflated with
+    dictionary: Option<u32>,
+    method: CompressionMethod,
+    // time after which the file may be purged, in whatever unit the caller uses
+    expires_at: Option<u64>,
+    // data is the target path of a symlink rather than file contents
+    symlink: bool,
+    // (part number, part count) of a split file, see File
+    part: Option<(u32, u32)>,
+    // data is a binary diff against a base with this crc, see insert_file_delta
+    delta: Option<u32>,
+}
+
+// metadata is stored as a list of fields so new ones can be added later while still
+// reading files written before them
+// variants are identified by index, so new ones must only ever be appended
+#[derive(Debug, Encode, Decode)]
+enum MetaField {
+    Dictionary(u32),
+    // only present when the data was stored raw, deflate is the default
+    Stored,
+    Expires(u64),
+    Symlink,
+    Part { index: u32, count: u32 },
+    Delta(u32),
+    Gzip,
+    Custom(u32),
+}
+
+impl FileMeta {
+    fn to_fields(&self) -> Vec<MetaField> {
+        let mut fields = Vec::new();
+
+        if let Some(crc) = self.dictionary {
+            fields.push(MetaField::Dictionary(crc));
+        }
+
+        match self.method {
+            CompressionMethod::Deflate => {}
+            CompressionMethod::Store => fields.push(MetaField::Stored),
+            CompressionMethod::Gzip => fields.push(MetaField::Gzip),
+            CompressionMethod::Custom(id) => fields.push(MetaField::Custom(id)),
+        }
+
+        if let Some(time) = self.expires_at {
+            fields.push(MetaField::Expires(time));
+        }
+
+        if self.symlink {
+            fields.push(MetaField::Symlink);
+        }
+
+        if let Some((index, count)) = self.part {
+            fields.push(MetaField::Part { index, count });
+        }
+
+        if let Some(crc) = self.delta {
+            fields.push(MetaField::Delta(crc));
+        }
+
+        fields
+    }
+
+    fn from_fields(fields: Vec<MetaField>) -> Self {
+        let mut meta = Self::default();
+
+        for field in fields {
+            match field {
+                MetaField::Dictionary(crc) => meta.dictionary = Some(crc),
+                MetaField::Stored => meta.method = CompressionMethod::Store,
+                MetaField::Expires(time) => meta.expires_at = Some(time),
+                MetaField::Symlink => meta.symlink = true,
+                MetaField::Part { index, count } => meta.part = Some((index, count)),
+                MetaField::Delta(crc) => meta.delta = Some(crc),
+                MetaField::Gzip => meta.method = CompressionMethod::Gzip,
+                MetaField::Custom(id) => meta.method = CompressionMethod::Custom(id),
+            }
+        }
+
+        meta
+    }
 }
 
-impl File<'_> {
+impl<'a> File<'a> {
     // Decode data contained with deflate
     // While I'd love to make this a deref instead, there would be a hidden cost due to the vec allocation
-    fn decode_data(&self) -> Result<Vec<u8>, PngFilesError> {
-        let mut writer = DeflateDecoder::new(Vec::new());
-        writer.write_all(&self.data)?;
-        Ok(writer.finish()?)
+    // Stored data is handed back as is, so it stays borrowed if it was
+    //
+    // `dictionary` must be the dictionary `meta` refers to, if any, and `decoders` the
+    // registered decoders for custom methods
+    fn decode_data(
+        self,
+        max: usize,
+        dictionary: Option<&[u8]>,
+        decoders: &Decoders,
+    ) -> Result<Cow<'a, [u8]>, PngFilesError> {
+        match self.meta.method {
+            CompressionMethod::Deflate => {
+                deflate::inflate(&self.data, max, dictionary).map(Cow::Owned)
+            }
+
+            CompressionMethod::Gzip => deflate::gunzip(&self.data, max).map(Cow::Owned),
+
+            CompressionMethod::Store => {
+                if self.data.len() > max {
+                    Err(PngFilesError::DecompressLimit(max))?;
+                }
+
+                Ok(self.data)
+            }
+
+            CompressionMethod::Custom(id) => {
+                let decoder = decoders.get(&id).ok_or_else(|| no_decoder(id))?;
+                let data = decoder(&self.data, max)?;
+
+                // the decoder is trusted to stop early, but the limit holds either way
+                if data.len() > max {
+                    Err(PngFilesError::DecompressLimit(max))?;
+                }
+
+                Ok(Cow::Owned(data))
+            }
+        }
+    }
+
+    // like decode_data, but only the first `len` bytes of the decoded data
+    fn decode_prefix(
+        &self,
+        len: usize,
+        dictionary: Option<&[u8]>,
+    ) -> Result<Vec<u8>, PngFilesError> {
+        match self.meta.method {
+            CompressionMethod::Deflate => deflate::inflate_prefix(&self.data, len, dictionary),
+            CompressionMethod::Gzip => deflate::gunzip_prefix(&self.data, len),
+            CompressionMethod::Store => Ok(self.data[..len.min(self.data.len())].to_vec()),
+            CompressionMethod::Custom(id) => Err(no_decoder(id)),
+        }
     }
 }
 
+fn no_decoder(id: u32) -> PngFilesError {
+    PngFilesError::Msg(Cow::Owned(format!(
+        "No decoder registered for compression method {id}"
+    )))
+}
+
 pub struct Png {
     chunks: Vec<PngChunk>,
-    capacity: usize,
+    max_decompressed: usize,
+    unknown_chunk_policy: UnknownChunkPolicy,
+    auto_comment: bool,
+    deflate_options: DeflateOptions,
+    // file chunks are written without a crc
+    fast_mode: bool,
+    // stored data size above which a file is split into parts
+    max_chunk_size: usize,
+    // deflate whole file chunks once more, see set_deflate_keys
+    deflate_keys: bool,
+    // written back out as-is, PNG_HEADER unless parsed with a different signature
+    signature: [u8; 8],
+    // decoders for CompressionMethod::Custom
+    decoders: Decoders,
+    // key to the index of its file chunk, see find_file_index
+    // dropped by chunks_mut, so anything adding, removing or moving chunks must go through it
+    file_index: OnceCell<HashMap<String, usize>>,
+    // lookups find_file_index did by scanning since the chunks last changed
+    scans: Cell<u32>,
+}
+
+// chunk types with their lengths and the embedded keys, but never any chunk data
+impl fmt::Debug for Png {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Png")
+            .field("chunk_count", &self.chunks.len())
+            .field("chunks", &self.chunks)
+            .field(
+                "files",
+                &self
+                    .chunks
+                    .iter()
+                    .filter_map(|c| c.chunk_type.get_key())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
 }
 
 struct PngChunk {
@@ -70,15 +519,46 @@ impl Deref for PngChunk {
 
 #[derive(PartialEq, Eq)]
 enum ChunkType {
+    // always exactly 4 bytes, only build this through `ChunkType::png`
     Png(String),
     File { key: String },
+    // any part of a split file but the first, see File
+    FilePart { key: String },
+    // a file chunk deflated as a whole, see PACKED_CHUNK_TYPE
+    PackedFile { key: String },
+}
+
+// e.g. `IDAT (8192 bytes)`, compact enough to list every chunk
+impl fmt::Debug for PngChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes)", self.chunk_type.as_ref(), self.len)
+    }
 }
 
 impl ChunkType {
+    /// A regular chunk type, which must be exactly 4 bytes
+    fn png(chunk_type: &str) -> Result<Self, PngFilesError> {
+        if chunk_type.len() != 4 {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Chunk type {chunk_type} is not 4 bytes long"
+            ))))?;
+        }
+
+        Ok(Self::Png(chunk_type.to_owned()))
+    }
+
     /// Get the key for ChunkType::File
     fn get_key(&self) -> Option<&str> {
         match self {
-            Self::File { key } => Some(key),
+            Self::File { key } | Self::PackedFile { key } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Get the key for ChunkType::File, or the file a ChunkType::FilePart belongs to
+    fn file_key(&self) -> Option<&str> {
+        match self {
+            Self::File { key } | Self::FilePart { key } | Self::PackedFile { key } => Some(key),
             _ => None,
         }
     }
@@ -86,6 +566,30 @@ impl ChunkType {
     fn as_bytes(&self) -> &[u8] {
         self.as_ref().as_bytes()
     }
+
+    // property bits are bit 5 of each byte (lowercase = 1)
+    // http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html#Chunk-naming-conventions
+    fn property_bit(&self, idx: usize) -> bool {
+        self.as_bytes().get(idx).is_some_and(|b| b & 0x20 != 0)
+    }
+
+    /// Ancillary bit (first byte)
+    fn is_ancillary(&self) -> bool {
+        self.property_bit(0)
+    }
+
+    /// Safe-to-copy bit (fourth byte)
+    fn is_safe_to_copy(&self) -> bool {
+        self.property_bit(3)
+    }
+
+    /// Whether this chunk type is one defined by the PNG spec (or is our own file chunk)
+    fn is_known(&self) -> bool {
+        match self {
+            Self::Png(_type) => KNOWN_CHUNK_TYPES.contains(&_type.as_str()),
+            Self::File { .. } | Self::FilePart { .. } | Self::PackedFile { .. } => true,
+        }
+    }
 }
 
 impl AsRef<str> for ChunkType {
@@ -93,49 +597,422 @@ impl AsRef<str> for ChunkType {
         match self {
             ChunkType::Png(_type) => _type,
             ChunkType::File { .. } => CHUNK_TYPE,
+            ChunkType::FilePart { .. } => PART_CHUNK_TYPE,
+            ChunkType::PackedFile { .. } => PACKED_CHUNK_TYPE,
         }
     }
 }
 
 enum DataSource {
-    Range {
-        data: Rc<Vec<u8>>,
-        range: Range<usize>,
-    },
+    Range { data: Buffer, range: Range<usize> },
 
     Data(Vec<u8>),
 }
 
+// the input bytes, shared between all chunks that were parsed out of them
+#[derive(Clone)]
+enum Buffer {
+    Owned(Rc<Vec<u8>>),
+    Shared(Arc<[u8]>),
+    #[cfg(feature = "memmap")]
+    Mapped(Rc<memmap2::Mmap>),
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Buffer::Owned(data) => data,
+            Buffer::Shared(data) => data,
+            #[cfg(feature = "memmap")]
+            Buffer::Mapped(data) => data,
+        }
+    }
+}
+
+// property bits are the same as in `ChunkType::property_bit`, checked on raw bytes
+// since an invalid type may not even be utf8
+fn validate_chunk_type(
+    chunk_type: &[u8; 4],
+    validation: ChunkTypeValidation,
+) -> Result<(), PngFilesError> {
+    let letters = chunk_type.iter().all(u8::is_ascii_alphabetic);
+    let private = chunk_type[1] & 0x20 != 0;
+    let reserved = chunk_type[2] & 0x20 != 0;
+
+    let valid = match validation {
+        ChunkTypeValidation::Strict => letters && !reserved,
+        ChunkTypeValidation::AllowPrivate => letters && (private || !reserved),
+        ChunkTypeValidation::NoValidation => true,
+    };
+
+    if !valid {
+        Err(PngFilesError::Msg(Cow::Owned(format!(
+            "Invalid chunk type {}",
+            String::from_utf8_lossy(chunk_type)
+        ))))?;
+    }
+
+    Ok(())
+}
+
+// calculate crc from chunk type first THEN data
+fn chunk_crc(chunk_type: &[u8], data: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(chunk_type);
+    h.update(data);
+    h.finalize()
+}
+
+// 64-bit FNV-1a, simple and with a fixed output unlike std's hashers
+fn fnv1a<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
 impl PngChunk {
+    // build a regular chunk, calculating its crc
+    fn new(chunk_type: &str, data: Vec<u8>) -> Result<Self, PngFilesError> {
+        Self::build(ChunkType::png(chunk_type)?, data, true)
+    }
+
+    // build a file chunk out of an already encoded `File`
+    // fast mode leaves the crc zeroed, see `Png::set_fast_mode`
+    fn new_file(key: &str, data: Vec<u8>, fast_mode: bool) -> Result<Self, PngFilesError> {
+        let chunk_type = ChunkType::File {
+            key: key.to_owned(),
+        };
+
+        Self::build(chunk_type, data, !fast_mode)
+    }
+
+    // like new_file, for any part of a split file but the first
+    fn new_file_part(key: &str, data: Vec<u8>, fast_mode: bool) -> Result<Self, PngFilesError> {
+        let chunk_type = ChunkType::FilePart {
+            key: key.to_owned(),
+        };
+
+        Self::build(chunk_type, data, !fast_mode)
+    }
+
+    // like new_file, for a file deflated as a whole, see PACKED_CHUNK_TYPE
+    fn new_packed_file(key: &str, data: Vec<u8>, fast_mode: bool) -> Result<Self, PngFilesError> {
+        let chunk_type = ChunkType::PackedFile {
+            key: key.to_owned(),
+        };
+
+        Self::build(chunk_type, data, !fast_mode)
+    }
+
+    fn build(chunk_type: ChunkType, data: Vec<u8>, with_crc: bool) -> Result<Self, PngFilesError> {
+        let len = data.len();
+
+        if len > u32::MAX as usize {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "Data cannot be bigger than u32::MAX bytes",
+            )))?;
+        }
+
+        let crc = if with_crc {
+            chunk_crc(chunk_type.as_bytes(), &data)
+        } else {
+            0
+        };
+
+        Ok(PngChunk {
+            crc,
+            source: DataSource::Data(data),
+            chunk_type,
+            len: len as u32,
+        })
+    }
+
+    // whether this is a tEXt comment generated by set_auto_comment
+    fn is_auto_comment(&self) -> bool {
+        self.chunk_type.as_ref() == "tEXt"
+            && self.starts_with(AUTO_COMMENT_PREFIX.as_bytes())
+            && self.ends_with(AUTO_COMMENT_SUFFIX.as_bytes())
+    }
+
+    // crc of the chunk's current type + data
+    fn compute_crc(&self) -> u32 {
+        chunk_crc(self.chunk_type.as_bytes(), self)
+    }
+
     // output a perfect representation of the chunk in binary
-    pub fn into_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         // 4 - len
         // 4 - chunk type
         // data len
         // 4 - crc
         let mut chunk: Vec<u8> = Vec::with_capacity(4 + 4 + (*self).len() + 4);
+        self.extend_bytes(&mut chunk);
+
+        chunk
+    }
+
+    // append the chunk's binary representation to `bytes`
+    fn extend_bytes(&self, bytes: &mut Vec<u8>) {
+        debug_assert_eq!(self.chunk_type.as_bytes().len(), 4);
+
         // all numbers are BE
         // len
-        chunk.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
         // chunk type
-        chunk.extend_from_slice(self.chunk_type.as_bytes());
+        bytes.extend_from_slice(self.chunk_type.as_bytes());
         // data
-        chunk.extend_from_slice(&self);
+        bytes.extend_from_slice(self);
         // crc
-        chunk.extend_from_slice(&self.crc.to_be_bytes());
+        bytes.extend_from_slice(&self.crc.to_be_bytes());
+    }
 
-        chunk
+    // same as to_bytes, but straight into a writer
+    // returns the number of bytes written
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<usize, PngFilesError> {
+        debug_assert_eq!(self.chunk_type.as_bytes().len(), 4);
+
+        writer.write_all(&self.len.to_be_bytes())?;
+        writer.write_all(self.chunk_type.as_bytes())?;
+        writer.write_all(self)?;
+        writer.write_all(&self.crc.to_be_bytes())?;
+
+        Ok(4 + 4 + self.len() + 4)
     }
 }
 
 impl Png {
+    /// Parse a PNG, taking ownership of its bytes
+    /// Chunks refer into `data` instead of copying it, so nothing borrowed from the caller
+    /// has to outlive the returned `Png`
     pub fn new(data: Vec<u8>) -> Result<Self, PngFilesError> {
+        Self::parse(
+            Buffer::Owned(Rc::new(data)),
+            ChunkTypeValidation::default(),
+            PNG_HEADER,
+        )
+    }
+
+    /// Parse a PNG, checking chunk types as strictly as `validation` says
+    /// `new` uses `ChunkTypeValidation::Strict`
+    pub fn new_with_validation(
+        data: Vec<u8>,
+        validation: ChunkTypeValidation,
+    ) -> Result<Self, PngFilesError> {
+        Self::parse(Buffer::Owned(Rc::new(data)), validation, PNG_HEADER)
+    }
+
+    /// Parse a PNG without verifying any chunk crcs, for files that only ever come from
+    /// this crate itself, e.g. written in fast mode
+    ///
+    /// The result is in fast mode, see `set_fast_mode`
+    pub fn new_trusted(data: Vec<u8>) -> Result<Self, PngFilesError> {
+        let (mut png, _) = Self::new_with_crc_policy(data, CrcPolicy::Ignore)?;
+        png.fast_mode = true;
+
+        Ok(png)
+    }
+
+    /// Parse a PNG, handling crc mismatches as `policy` says, for reading files made by
+    /// tools that write bad (or zeroed) crcs. `new` uses `CrcPolicy::Strict`
+    ///
+    /// Returns the mismatches found along with the PNG, which is only ever non-empty with
+    /// `CrcPolicy::WarnOnly`. Mismatching chunks keep their stored crc, use `recompute_crcs`
+    /// to fix them before writing the PNG back out
+    pub fn new_with_crc_policy(
+        data: Vec<u8>,
+        policy: CrcPolicy,
+    ) -> Result<(Self, Vec<CrcWarning>), PngFilesError> {
+        Self::parse_from(
+            Buffer::Owned(Rc::new(data)),
+            0,
+            ChunkTypeValidation::default(),
+            PNG_HEADER,
+            false,
+            policy,
+        )
+        .map(|(png, _, warnings)| (png, warnings))
+    }
+
+    /// Parse a file using PNG's chunk layout but another signature, e.g. `MNG_SIGNATURE`
+    /// or `JNG_SIGNATURE`. The signature is kept and written back out on serialization
+    ///
+    /// New files still go before the first IEND if there is one, which in an MNG is the
+    /// end of its first embedded image rather than of the whole file
+    pub fn new_with_signature(data: Vec<u8>, signature: [u8; 8]) -> Result<Self, PngFilesError> {
+        Self::parse(
+            Buffer::Owned(Rc::new(data)),
+            ChunkTypeValidation::default(),
+            signature,
+        )
+    }
+
+    /// A blank (black) 8-bit grayscale image, as a cover for embedding files when there's
+    /// no real image to use
+    pub fn new_blank(width: u32, height: u32) -> Result<Self, PngFilesError> {
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "Image size must be between 1 and 2^31 - 1 pixels per side",
+            )))?;
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        // bit depth 8, grayscale, deflate, adaptive filtering, no interlace
+        ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        // every scanline is a filter type byte (none) followed by one byte per pixel
+        let row = vec![0; width as usize + 1];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        for _ in 0..height {
+            encoder.write_all(&row)?;
+        }
+        let idat = encoder.finish()?;
+
+        let mut bytes = PNG_HEADER.to_vec();
+        for (chunk_type, data) in [("IHDR", ihdr), ("IDAT", idat), ("IEND", Vec::new())] {
+            bytes.extend(PngChunk::new(chunk_type, data)?.to_bytes());
+        }
+
+        Self::new(bytes)
+    }
+
+    /// Whether `data` starts with the PNG signature
+    /// Only the signature is checked, the rest may still fail to parse
+    pub fn is_png(data: &[u8]) -> bool {
+        data.starts_with(&PNG_HEADER)
+    }
+
+    /// Check that `key` may be used for a file, with the same rules every insert applies
+    ///
+    /// Keys must be non-empty, at most 4096 bytes long, and contain no control characters
+    /// (which includes newlines and NUL). Slashes are allowed, see `tree`
+    pub fn validate_key(key: &str) -> Result<(), PngFilesError> {
+        if key.is_empty() {
+            Err(PngFilesError::Msg(Cow::Borrowed("Key is empty")))?;
+        }
+
+        if key.len() > MAX_KEY_LEN {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Key is {} bytes long, the limit is {MAX_KEY_LEN}",
+                key.len()
+            ))))?;
+        }
+
+        if key.chars().any(char::is_control) {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Key {key:?} contains control characters"
+            ))))?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse a PNG out of bytes that are already shared (e.g. an `Arc<[u8]>` cache entry)
+    /// without copying them into a new `Vec`
+    ///
+    /// Chunks borrow straight out of `data`, so the whole buffer stays alive for as long
+    /// as this `Png` still holds any chunk from it, even if only a small part is used
+    pub fn from_shared(data: Arc<[u8]>) -> Result<Self, PngFilesError> {
+        Self::parse(
+            Buffer::Shared(data),
+            ChunkTypeValidation::default(),
+            PNG_HEADER,
+        )
+    }
+
+    /// Open a PNG on disk by memory mapping it, so only the parts that are actually
+    /// used (e.g. by `get_file`) are ever read into memory
+    ///
+    /// The file must not be modified or truncated, by this or any other process, while
+    /// this `Png` or anything parsed from it is alive; doing so is undefined behavior.
+    /// That includes `save` to the same path, which truncates the file while still reading
+    /// from it; use `into_bytes` and write those instead. If the file can't be mapped, it
+    /// is read into memory instead
+    #[cfg(feature = "memmap")]
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Self, PngFilesError> {
+        let path = path.as_ref();
+
+        let file =
+            fs::File::open(path).map_err(|e| PngFilesError::from(e).with_path("open", path))?;
+
+        // SAFETY: the caller guarantees the file isn't modified while mapped, see above
+        let data = match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(map) => Buffer::Mapped(Rc::new(map)),
+
+            Err(_) => {
+                let data =
+                    fs::read(path).map_err(|e| PngFilesError::from(e).with_path("read", path))?;
+                Buffer::Owned(Rc::new(data))
+            }
+        };
+
+        Self::parse(data, ChunkTypeValidation::default(), PNG_HEADER)
+    }
+
+    /// Parse every PNG in `data`, for files made of several PNGs concatenated together
+    ///
+    /// After each image's IEND, parsing continues at the next PNG signature; anything
+    /// in between, or after the last image, is skipped. All images share `data` instead
+    /// of copying their part of it
+    pub fn parse_all(data: Vec<u8>) -> Result<Vec<Self>, PngFilesError> {
+        let data = Buffer::Owned(Rc::new(data));
+
+        let mut pngs = Vec::new();
+        let mut start = 0;
+
+        loop {
+            let (png, end, _) = Self::parse_from(
+                data.clone(),
+                start,
+                ChunkTypeValidation::default(),
+                PNG_HEADER,
+                true,
+                CrcPolicy::Strict,
+            )?;
+            pngs.push(png);
+
+            let next = data[end..]
+                .windows(PNG_HEADER.len())
+                .position(|w| w == PNG_HEADER);
+
+            match next {
+                Some(offset) => start = end + offset,
+                None => break,
+            }
+        }
+
+        Ok(pngs)
+    }
+
+    fn parse(
+        data: Buffer,
+        validation: ChunkTypeValidation,
+        signature: [u8; 8],
+    ) -> Result<Self, PngFilesError> {
+        Self::parse_from(data, 0, validation, signature, false, CrcPolicy::Strict)
+            .map(|(png, _, _)| png)
+    }
+
+    // parse the image starting at `start`, returning it along with where it ended and any
+    // crc mismatches `crc_policy` let through
+    // `stop_at_iend` stops right after IEND instead of reading chunks until the end of data
+    fn parse_from(
+        data: Buffer,
+        start: usize,
+        validation: ChunkTypeValidation,
+        signature: [u8; 8],
+        stop_at_iend: bool,
+        crc_policy: CrcPolicy,
+    ) -> Result<(Self, usize, Vec<CrcWarning>), PngFilesError> {
         let file_len = data.len();
-        let data = Rc::new(data);
 
         // enclose in scope to make sure borrow is dropped
 
-        let mut cursor = Cursor::new(&**data);
+        let mut cursor = Cursor::new(&*data);
+        cursor.set_position(start as u64);
 
         // validate header
         //
@@ -143,16 +1020,21 @@ impl Png {
         // http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html
         //
 
-        let mut buf_res = [0; PNG_HEADER.len()];
-        cursor.read_exact(&mut buf_res)?;
+        let mut buf_res = [0; 8];
 
-        if PNG_HEADER != buf_res {
+        // a file too short to hold the header isn't a PNG either
+        if cursor.read_exact(&mut buf_res).is_err() || signature != buf_res {
             Err(PngFilesError::Msg(Cow::Borrowed(
-                "Input file is not PNG format",
+                if signature == PNG_HEADER {
+                    "Input file is not PNG format"
+                } else {
+                    "Input file does not have the expected signature"
+                },
             )))?;
         }
 
         let mut chunks = Vec::new();
+        let mut warnings = Vec::new();
 
         loop {
             if cursor.position() as usize >= file_len {
@@ -172,32 +1054,45 @@ impl Png {
             // borrow slice of type + data for crc check later
             // chunk type - 4 bytes
             // data len - variable
-            let crc_data =
-                cursor
-                    .get_ref()
-                    .get(cur_pos..cur_pos + 4 + len)
-                    .ok_or(PngFilesError::Msg(Cow::Borrowed(
-                        "Invalid chunk (type or data missing)",
-                    )))?;
-            let data_crc = crc32fast::hash(crc_data);
+            // checked, since on 32-bit targets a huge len could wrap around usize
+            let crc_end = cur_pos
+                .checked_add(4)
+                .and_then(|pos| pos.checked_add(len))
+                .ok_or(PngFilesError::Msg(Cow::Borrowed(
+                    "Chunk length overflows address space",
+                )))?;
+            let crc_data = cursor
+                .get_ref()
+                .get(cur_pos..crc_end)
+                .ok_or(PngFilesError::Msg(Cow::Borrowed(
+                    "Invalid chunk (type or data missing)",
+                )))?;
+            let data_crc = (crc_policy != CrcPolicy::Ignore).then(|| crc32fast::hash(crc_data));
 
             let mut chunk_type = [0; 4];
             cursor
                 .read_exact(&mut chunk_type)
                 .map_err(|_| PngFilesError::Msg(Cow::Borrowed("Failed to read chunk type")))?;
+            validate_chunk_type(&chunk_type, validation)?;
             let chunk_type = std::str::from_utf8(&chunk_type)
                 .map_err(|_| PngFilesError::Msg(Cow::Borrowed("Invalid chunk type")))?;
 
             let range_pos: usize = cursor.position().try_into().map_err(|_| {
                 PngFilesError::Msg(Cow::Borrowed("Failed to convert index to usize"))
             })?;
-            let chunk_data = if chunk_type == CHUNK_TYPE {
+            let range_end = range_pos
+                .checked_add(len)
+                .ok_or(PngFilesError::Msg(Cow::Borrowed(
+                    "Chunk length overflows address space",
+                )))?;
+            let is_file = [CHUNK_TYPE, PART_CHUNK_TYPE, PACKED_CHUNK_TYPE].contains(&chunk_type);
+            let chunk_data = if is_file {
                 // if it's a data chunk we're interested in, save the data
                 // slice the ref so we can borrow data instead of needing to allocate
                 Some(
                     cursor
                         .get_ref()
-                        .get(range_pos..range_pos + len)
+                        .get(range_pos..range_end)
                         .ok_or(PngFilesError::Msg(Cow::Borrowed("fiLe data not found")))?,
                 )
             } else {
@@ -212,28 +1107,48 @@ impl Png {
                 .map_err(|_| PngFilesError::Msg(Cow::Borrowed("Failed to read crc")))?;
 
             // validate chunk, cause why not
-            if data_crc != crc {
-                Err(PngFilesError::Msg(Cow::Borrowed(
-                    "Crc check failed; PNG file is corrupted",
-                )))?;
+            if let Some(data_crc) = data_crc.filter(|&data_crc| data_crc != crc) {
+                if crc_policy == CrcPolicy::Strict {
+                    Err(PngFilesError::Msg(Cow::Borrowed(
+                        "Crc check failed; PNG file is corrupted",
+                    )))?;
+                }
+
+                warnings.push(CrcWarning {
+                    index: chunks.len(),
+                    chunk_type: chunk_type.to_owned(),
+                    stored: crc,
+                    computed: data_crc,
+                });
             }
 
-            let chunk = if chunk_type == CHUNK_TYPE {
+            // an empty fiLe chunk can't hold a file (there's not even a key), so it's kept
+            // around untouched like any other chunk instead of failing to decode
+            let chunk = if is_file && len > 0 {
                 // our special file chunk
                 let chunk_data = chunk_data.unwrap();
 
-                let file = Self::decode_file(chunk_data)?;
-
+                let key = if chunk_type == PACKED_CHUNK_TYPE {
+                    Self::peek_packed_key(chunk_data)
+                } else {
+                    Self::peek_key(chunk_data).map(str::to_owned)
+                }
+                // chunk data starts after len + chunk type
+                .map_err(|e| e.with_chunk(chunks.len(), Some(range_pos - 8)))?;
+
                 PngChunk {
-                    chunk_type: ChunkType::File {
-                        key: file.key.to_owned(),
+                    chunk_type: match chunk_type {
+                        // parts after the first aren't files on their own
+                        PART_CHUNK_TYPE => ChunkType::FilePart { key },
+                        PACKED_CHUNK_TYPE => ChunkType::PackedFile { key },
+                        _ => ChunkType::File { key },
                     },
 
                     source: DataSource::Range {
                         data: data.clone(),
                         range: Range {
                             start: range_pos,
-                            end: range_pos + len,
+                            end: range_end,
                         },
                     },
 
@@ -244,12 +1159,12 @@ impl Png {
             } else {
                 // regular chunk
                 PngChunk {
-                    chunk_type: ChunkType::Png(chunk_type.to_owned()),
+                    chunk_type: ChunkType::png(chunk_type)?,
                     source: DataSource::Range {
                         data: data.clone(),
                         range: Range {
                             start: range_pos,
-                            end: range_pos + len,
+                            end: range_end,
                         },
                     },
                     crc,
@@ -259,143 +1174,3078 @@ impl Png {
             };
 
             chunks.push(chunk);
+
+            if stop_at_iend && chunk_type == "IEND" {
+                break;
+            }
         }
 
-        Ok(Self {
+        let end = cursor.position() as usize;
+
+        let png = Self {
             chunks,
-            capacity: file_len,
-        })
+            max_decompressed: DEFAULT_MAX_DECOMPRESSED,
+            unknown_chunk_policy: UnknownChunkPolicy::default(),
+            auto_comment: false,
+            deflate_options: DeflateOptions::default(),
+            fast_mode: false,
+            max_chunk_size: usize::MAX,
+            deflate_keys: false,
+            signature,
+            decoders: HashMap::new(),
+            file_index: OnceCell::new(),
+            scans: Cell::new(0),
+        };
+
+        Ok((png, end, warnings))
+    }
+
+    /// Register the decoder for files stored with `CompressionMethod::Custom(id)`, replacing
+    /// any registered for the same id before
+    ///
+    /// This lets files be compressed with codecs this crate doesn't know about: encode the
+    /// data yourself, insert it with `insert_file_with_method(key, data, Custom(id), ..)`,
+    /// and register a decoder for `id` on every `Png` or `IndexedPng` that reads it back,
+    /// e.g. `png.register_decoder(1, |data, max| my_codec::decode(data, max))`. Without
+    /// one, reading such a file fails. The built-in methods can't be overridden
+    pub fn register_decoder<F>(&mut self, id: u32, decoder: F)
+    where
+        F: Fn(&[u8], usize) -> Result<Vec<u8>, PngFilesError> + 'static,
+    {
+        self.decoders.insert(id, Box::new(decoder));
+    }
+
+    /// Set the maximum size a single file is allowed to inflate to when retrieved
+    /// Defaults to `DEFAULT_MAX_DECOMPRESSED`
+    pub fn set_max_decompressed(&mut self, max: usize) {
+        self.max_decompressed = max;
+    }
+
+    /// Builder version of `set_max_decompressed`, for chaining right after parsing
+    /// Parse time options like the crc policy are picked with their own constructor instead
+    pub fn with_max_decompressed(mut self, max: usize) -> Self {
+        self.set_max_decompressed(max);
+        self
+    }
+
+    /// Set the deflate level (0 - 9, higher is smaller but slower) files are inserted with
+    /// Levels above 9 are treated as 9. Defaults to 9
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.deflate_options.level = level.min(9);
+    }
+
+    /// Builder version of `set_compression_level`
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.set_compression_level(level);
+        self
+    }
+
+    /// Set what happens to unknown chunks on serialization
+    /// Defaults to `UnknownChunkPolicy::KeepAll`
+    pub fn set_unknown_chunk_policy(&mut self, policy: UnknownChunkPolicy) {
+        self.unknown_chunk_policy = policy;
+    }
+
+    /// Builder version of `set_unknown_chunk_policy`
+    pub fn with_unknown_chunk_policy(mut self, policy: UnknownChunkPolicy) -> Self {
+        self.set_unknown_chunk_policy(policy);
+        self
+    }
+
+    /// When enabled, serializing adds (or updates) a tEXt "Comment" chunk saying how many
+    /// files are embedded, so the image describes itself in regular metadata viewers
+    /// Off by default
+    pub fn set_auto_comment(&mut self, enabled: bool) {
+        self.auto_comment = enabled;
+    }
+
+    /// Builder version of `set_auto_comment`
+    pub fn with_auto_comment(mut self, enabled: bool) -> Self {
+        self.set_auto_comment(enabled);
+        self
+    }
+
+    /// In fast mode, file chunks are written with zeroed crcs instead of calculating them,
+    /// and existing file chunks have theirs zeroed right away. Turning it off recalculates them
+    ///
+    /// Only meant for images that never leave a closed loop and are read back with
+    /// `new_trusted`: the output is not a standard PNG, and image viewers as well as `new`
+    /// may reject it. Off by default
+    pub fn set_fast_mode(&mut self, enabled: bool) {
+        self.fast_mode = enabled;
+
+        for chunk in &mut self.chunks {
+            if chunk.chunk_type.file_key().is_some() {
+                chunk.crc = if enabled { 0 } else { chunk.compute_crc() };
+            }
+        }
+    }
+
+    /// Builder version of `set_fast_mode`
+    pub fn with_fast_mode(mut self, enabled: bool) -> Self {
+        self.set_fast_mode(enabled);
+        self
+    }
+
+    /// Whether fast mode is enabled, see `set_fast_mode`
+    pub fn fast_mode(&self) -> bool {
+        self.fast_mode
+    }
+
+    /// Split files whose stored (compressed) data is over `bytes` into a `fiLe` chunk
+    /// followed by `fiLp` chunks, for readers that can't handle very large chunks. Getting
+    /// a file joins its parts back together transparently. Only affects files inserted from
+    /// now on
+    /// Each chunk is a bit bigger than its part of the data, since it also has the key
+    /// Unlimited by default
+    pub fn set_max_chunk_size(&mut self, bytes: usize) {
+        self.max_chunk_size = bytes.max(1);
+    }
+
+    /// Builder version of `set_max_chunk_size`
+    pub fn with_max_chunk_size(mut self, bytes: usize) -> Self {
+        self.set_max_chunk_size(bytes);
+        self
+    }
+
+    /// Deflate each file chunk once more as a whole, so its key and metadata are compressed
+    /// along with the already compressed data. Such chunks are `fiLz` instead of `fiLe`, and
+    /// a file is only stored that way when it comes out smaller. Split files never are
+    ///
+    /// Every chunk is deflated on its own, so this only pays off for keys that repeat
+    /// themselves, like long paths with the same directory names over and over. Prefixes
+    /// shared between keys aren't found, and for short keys the deflate framing costs more
+    /// than it saves. Reading such a file also has to inflate it twice, and parsing has to
+    /// inflate the start of it to find its key. Only affects files inserted from now on
+    /// Off by default
+    pub fn set_deflate_keys(&mut self, enabled: bool) {
+        self.deflate_keys = enabled;
+    }
+
+    /// Builder version of `set_deflate_keys`
+    pub fn with_deflate_keys(mut self, enabled: bool) -> Self {
+        self.set_deflate_keys(enabled);
+        self
+    }
+
+    /// Whether a file with this key is embedded
+    pub fn contains_file(&self, key: &str) -> bool {
+        self.find_file_index(key).is_some()
+    }
+
+    /// Number of embedded files
+    pub fn file_count(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|c| c.chunk_type.get_key().is_some())
+            .count()
+    }
+
+    /// Keys of the embedded files in storage order, which is the order they were inserted
+    /// in (see `insert_file`), oldest first
+    pub fn files_in_order(&self) -> Vec<&str> {
+        self.chunks
+            .iter()
+            .filter_map(|c| c.chunk_type.get_key())
+            .collect()
+    }
+
+    /// A hash of the whole set of embedded files, e.g. for cache invalidation
+    ///
+    /// Combines every file's key with the crc of its stored chunk, independent of file
+    /// order and unaffected by any image chunk. It changes whenever a file is added, removed
+    /// or stored differently, which includes recompressing the same data at another level.
+    /// Stable across runs and versions, so it can be persisted
+    pub fn payload_digest(&self) -> u64 {
+        self.chunks
+            .iter()
+            .filter_map(|c| {
+                let key = c.chunk_type.file_key()?;
+                // fast mode leaves crcs zeroed
+                let crc = if c.crc == 0 { c.compute_crc() } else { c.crc };
+
+                Some(fnv1a(key.as_bytes().iter().chain(&crc.to_be_bytes())))
+            })
+            .fold(0, |digest, hash| digest ^ hash)
     }
 
-    /// Returns none if file failed to decode or was not found
+    /// Returns none if file failed to decode, was not found, or inflated past the max decompressed size
     pub fn get_file(&self, key: &str) -> Option<Vec<u8>> {
+        self.find_file_index(key)
+            .and_then(|idx| self.decode_chunk(&self.chunks[idx]).ok())
+    }
+
+    /// Get a file like `get_file`, without copying it if it was stored uncompressed
+    /// (`CompressionMethod::Store`); deflated files are inflated into an owned vec
+    ///
+    /// A borrowed result points straight into the PNG, so it keeps `self` borrowed for
+    /// as long as it lives. Use `into_owned` to keep the data past that
+    pub fn get_file_cow(&self, key: &str) -> Option<Cow<'_, [u8]>> {
+        self.find_file_index(key)
+            .and_then(|idx| self.decode_chunk_cow(&self.chunks[idx]).ok())
+    }
+
+    /// Get a file's data exactly as stored, without decompressing it: a complete gzip
+    /// stream for `CompressionMethod::Gzip`, raw deflate data for `Deflate`, and the file
+    /// itself for `Store`. See `compression_method` for which one it is
+    /// Returns none if the file failed to decode or was not found
+    pub fn get_file_raw(&self, key: &str) -> Option<Cow<'_, [u8]>> {
+        let chunk = &self.chunks[self.find_file_index(key)?];
+        let file = self.chunk_file(chunk).ok()?;
+
+        if file.meta.part.is_none() {
+            return Some(file.data);
+        }
+
+        let mut data = file.data.into_owned();
+        for part in self.file_parts(&file.key, &file.meta).ok()? {
+            data.extend_from_slice(&part);
+        }
+
+        Some(Cow::Owned(data))
+    }
+
+    /// Decode only the first `max_bytes` of a file, e.g. to check its type by its magic
+    /// bytes, stopping before inflating the rest. Returns the whole file if it's shorter
+    /// Returns none if the key was not found
+    pub fn get_file_prefix(
+        &self,
+        key: &str,
+        max_bytes: usize,
+    ) -> Result<Option<Vec<u8>>, PngFilesError> {
+        let Some(idx) = self.find_file_index(key) else {
+            return Ok(None);
+        };
+
+        let (file, dictionary) = self.whole_file(&self.chunks[idx])?;
+
+        // a custom decoder can only decode the whole file
+        if let CompressionMethod::Custom(_) = file.meta.method {
+            let mut data = self.decode_file_data(file, dictionary)?.into_owned();
+            data.truncate(max_bytes);
+
+            return Ok(Some(data));
+        }
+
+        // a prefix over the limit still fails, like the whole file would
+        let data = file.decode_prefix(
+            max_bytes.min(self.max_decompressed.saturating_add(1)),
+            dictionary,
+        )?;
+
+        if data.len() > self.max_decompressed {
+            Err(PngFilesError::DecompressLimit(self.max_decompressed))?;
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Key of the `index`th file in storage order (0 up to `file_count`)
+    pub fn file_key_at(&self, index: usize) -> Option<&str> {
+        self.file_chunk_at(index)
+            .and_then(|chunk| chunk.chunk_type.get_key())
+    }
+
+    /// Data of the `index`th file in storage order (0 up to `file_count`)
+    /// Returns none if file failed to decode, was not found, or inflated past the max decompressed size
+    pub fn get_file_by_index(&self, index: usize) -> Option<Vec<u8>> {
+        self.file_chunk_at(index)
+            .and_then(|chunk| self.decode_chunk(chunk).ok())
+    }
+
+    fn file_chunk_at(&self, index: usize) -> Option<&PngChunk> {
+        self.chunks
+            .iter()
+            .filter(|c| c.chunk_type.get_key().is_some())
+            .nth(index)
+    }
+
+    /// Information about a stored file, or none if it failed to decode or was not found
+    pub fn file_info(&self, key: &str) -> Option<FileInfo> {
+        let chunk = &self.chunks[self.find_file_index(key)?];
+
+        Some(FileInfo {
+            compressed_len: self.stored_len(chunk).ok()?,
+        })
+    }
+
+    /// Compressed and decompressed size of every file, in storage order
+    /// Inflates every file to measure it, so it's as expensive as reading them all
+    /// Files that fail to decode are left out
+    pub fn compression_stats(&self) -> Vec<FileStats> {
         self.chunks
             .iter()
-            .find(|&c| {
-                let chunk_type = c.chunk_type.as_ref();
+            .filter_map(|chunk| {
+                let key = chunk.chunk_type.get_key()?;
+                let compressed_len = self.stored_len(chunk).ok()?;
+                let decompressed_len = self.decode_chunk(chunk).ok()?.len();
 
-                if chunk_type == CHUNK_TYPE {
-                    c.chunk_type.get_key().unwrap() == key
+                let ratio = if decompressed_len == 0 {
+                    1.0
                 } else {
-                    false
-                }
+                    compressed_len as f64 / decompressed_len as f64
+                };
+
+                Some(FileStats {
+                    key: key.to_owned(),
+                    compressed_len,
+                    decompressed_len,
+                    ratio,
+                })
             })
-            .and_then(|c| Self::decode_file(c).ok().and_then(|f| f.decode_data().ok()))
+            .collect()
     }
 
-    // note: decoded file is NOT deflate decoded in order to allow for slice borrow
-    fn decode_file(data: &[u8]) -> Result<File<'_>, PngFilesError> {
-        let (file, _) =
-            bincode::borrow_decode_from_slice::<File<'_>, _>(data, bincode::config::standard())?;
+    /// Files grouped by directory, for path-style keys like `config/app.json`
+    ///
+    /// The directory is everything before the last `/` (an empty string for keys without
+    /// one), listed with each file's name and compressed size. Files that fail to decode
+    /// are left out
+    pub fn tree(&self) -> BTreeMap<String, Vec<(String, usize)>> {
+        let mut tree = BTreeMap::<String, Vec<_>>::new();
+
+        for (key, chunk) in self.file_chunks() {
+            let Ok(len) = self.stored_len(chunk) else {
+                continue;
+            };
+
+            let (dir, name) = key.rsplit_once('/').unwrap_or(("", key));
+            tree.entry(dir.to_owned())
+                .or_default()
+                .push((name.to_owned(), len));
+        }
 
-        Ok(file)
+        // file_chunks is unordered
+        for files in tree.values_mut() {
+            files.sort();
+        }
+
+        tree
     }
 
-    /// File data is encoded with deflate
-    fn encode_file(mut file: File<'_>) -> Result<Vec<u8>, PngFilesError> {
-        let mut deflater = DeflateEncoder::new(Vec::new(), Compression::best());
-        deflater.write_all(&file.data)?;
-        let data = deflater.finish()?;
-        file.data = Cow::Owned(data);
+    /// How many chunks of each type the image has, embedded files counting as the type
+    /// they're stored in (`fiLe`, or `fiLp` for later parts and `fiLz` when deflated whole)
+    pub fn chunk_type_histogram(&self) -> BTreeMap<String, usize> {
+        let mut histogram = BTreeMap::new();
 
-        let data = bincode::encode_to_vec::<File, _>(file, bincode::config::standard())?;
+        for chunk in &self.chunks {
+            *histogram
+                .entry(chunk.chunk_type.as_ref().to_owned())
+                .or_default() += 1;
+        }
 
-        Ok(data)
+        histogram
     }
 
-    /// Remove a file from png, returning whether one was removed or not
-    pub fn remove_file(&mut self, key: &str) -> bool {
-        let idx = self.chunks.iter().position(|c| {
-            let chunk_type = c.chunk_type.as_ref();
+    /// Type and data of every chunk that belongs to the image itself, in order
+    /// Chunks holding embedded files and their preset dictionaries are skipped
+    pub fn iter_png_chunks(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| match &chunk.chunk_type {
+                ChunkType::Png(t) if t != DICT_CHUNK_TYPE => Some((t.as_str(), &**chunk)),
+                _ => None,
+            })
+    }
 
-            if chunk_type == CHUNK_TYPE {
-                c.chunk_type.get_key().unwrap() == key
-            } else {
-                false
+    /// How a stored file's data was compressed, or none if it failed to decode or was not found
+    pub fn compression_method(&self, key: &str) -> Option<CompressionMethod> {
+        let chunk = &self.chunks[self.find_file_index(key)?];
+        let file = self.chunk_file(chunk).ok()?;
+
+        Some(file.meta.method)
+    }
+
+    /// Whether a stored file is a symlink, whose data is its target path
+    /// False if it failed to decode or was not found
+    pub fn is_symlink(&self, key: &str) -> bool {
+        self.find_file_index(key)
+            .and_then(|idx| self.chunk_file(&self.chunks[idx]).ok())
+            .is_some_and(|file| file.meta.symlink)
+    }
+
+    /// Whether a stored file is itself a PNG, which may have files of its own embedded
+    /// False if it failed to decode or was not found
+    pub fn is_embedded_png(&self, key: &str) -> bool {
+        self.get_file_cow(key)
+            .is_some_and(|data| Self::is_png(&data))
+    }
+
+    /// Try to fully decode every file, reporting success or failure per key
+    /// This inflates every file, so it's as expensive as extracting all of them
+    pub fn validate_files(&self) -> Vec<(String, Result<(), PngFilesError>)> {
+        self.chunks
+            .iter()
+            .filter_map(|c| {
+                let key = c.chunk_type.get_key()?;
+                Some((key.to_owned(), self.decode_chunk(c).map(|_| ())))
+            })
+            .collect()
+    }
+
+    /// Compare the embedded files of this image against `other`
+    ///
+    /// Files are compared by their inflated data, so the same file compressed differently
+    /// is not a change. Files that fail to decode are compared by their stored bytes
+    pub fn diff(&self, other: &Png) -> FileDiff {
+        let ours = self.file_chunks();
+        let theirs = other.file_chunks();
+
+        let mut diff = FileDiff::default();
+
+        for chunk in &self.chunks {
+            let Some(key) = chunk.chunk_type.get_key() else {
+                continue;
+            };
+
+            // only look at the chunk get_file would use
+            if !std::ptr::eq(ours[key], chunk) {
+                continue;
             }
-        });
 
-        if let Some(idx) = idx {
-            self.chunks.remove(idx);
-            true
-        } else {
-            false
+            let Some(&their_chunk) = theirs.get(key) else {
+                diff.removed.push(key.to_owned());
+                continue;
+            };
+
+            let same = match (self.decode_chunk(chunk), other.decode_chunk(their_chunk)) {
+                (Ok(ours), Ok(theirs)) => ours == theirs,
+                (Err(_), Err(_)) => **chunk == **their_chunk,
+                _ => false,
+            };
+
+            if !same {
+                diff.changed.push(key.to_owned());
+            }
+        }
+
+        for chunk in &other.chunks {
+            if let Some(key) = chunk.chunk_type.get_key() {
+                if !ours.contains_key(key) && std::ptr::eq(theirs[key], chunk) {
+                    diff.added.push(key.to_owned());
+                }
+            }
         }
+
+        diff
     }
 
-    /// insert file chunk into PNG
-    /// `replace` overwrites existing key if it exists
-    pub fn insert_file(
-        &mut self,
-        key: &str,
-        data: Vec<u8>,
-        replace: bool,
+    /// Decode every file into a map of key to data
+    /// Fails on the first file that doesn't decode, unlike `get_files`
+    pub fn extract_all(&self) -> Result<HashMap<String, Vec<u8>>, PngFilesError> {
+        self.file_chunks()
+            .into_iter()
+            .map(|(key, chunk)| Ok((key.to_owned(), self.decode_chunk(chunk)?)))
+            .collect()
+    }
+
+    /// Decode every file like `extract_all`, also descending into files that are PNGs
+    /// themselves (see `is_embedded_png`) and extracting their files too
+    ///
+    /// Nested files are keyed by the path of keys leading to them, e.g. `inner.png/a.txt`
+    /// for `a.txt` embedded in `inner.png`. The PNGs themselves are kept as well. An embedded
+    /// PNG that doesn't parse is kept as a plain file
+    pub fn extract_all_recursive(&self) -> Result<HashMap<String, Vec<u8>>, PngFilesError> {
+        let mut files = HashMap::new();
+        self.extract_nested("", 0, &mut files)?;
+
+        Ok(files)
+    }
+
+    fn extract_nested(
+        &self,
+        prefix: &str,
+        depth: usize,
+        files: &mut HashMap<String, Vec<u8>>,
     ) -> Result<(), PngFilesError> {
-        // find existing item with key if it exists
-        let idx = self.chunks.iter().position(|c| {
-            let chunk_type = c.chunk_type.as_ref();
+        if depth > MAX_NESTING {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Embedded PNGs are nested more than {MAX_NESTING} deep"
+            ))))?;
+        }
 
-            if chunk_type == CHUNK_TYPE {
-                c.chunk_type.get_key().unwrap() == key
-            } else {
-                false
+        for (key, data) in self.extract_all()? {
+            let key = format!("{prefix}{key}");
+
+            if Self::is_png(&data) {
+                // nested images are limited just like this one
+                if let Ok(nested) = Png::new(data.clone()) {
+                    let nested = nested.with_max_decompressed(self.max_decompressed);
+                    nested.extract_nested(&format!("{key}/"), depth + 1, files)?;
+                }
             }
-        });
 
-        // check that no key already exists in data
-        if !replace && idx.is_some() {
-            Err(PngFilesError::Msg(Cow::Borrowed("Key already in use")))?;
+            files.insert(key, data);
         }
 
-        let file = File {
-            key,
-            data: Cow::Borrowed(&data),
-        };
+        Ok(())
+    }
 
-        let data = Self::encode_file(file)?;
+    // map of every key to its file chunk
+    fn file_chunks(&self) -> HashMap<&str, &PngChunk> {
+        let mut files = HashMap::new();
+        for chunk in &self.chunks {
+            if let Some(key) = chunk.chunk_type.get_key() {
+                // first match wins, same as get_file
+                files.entry(key).or_insert(chunk);
+            }
+        }
 
-        // calculate crc from chunk type first THEN data
-        let mut h = crc32fast::Hasher::new();
-        h.update(CHUNK_TYPE.as_bytes());
-        h.update(&data);
-        let crc = h.finalize();
+        files
+    }
 
-        let len = data.len();
+    /// Decode several files at once, scanning the chunks only a single time
+    /// Each key is paired with its data, or none if it failed to decode or was not found
+    pub fn get_files<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(String, Option<Vec<u8>>)> {
+        let index = self.file_chunks();
 
-        if len > u32::MAX as usize {
-            Err(PngFilesError::Msg(Cow::Borrowed(
-                "Data cannot be bigger than u32::MAX bytes",
-            )))?;
+        keys.into_iter()
+            .map(|key| {
+                let data = index.get(key).and_then(|&c| self.decode_chunk(c).ok());
+
+                (key.to_owned(), data)
+            })
+            .collect()
+    }
+
+    /// Index of the chunk holding the file for `key`
+    ///
+    /// The first few lookups after the chunks change scan them, then an index is built so
+    /// that repeated lookups (e.g. serving many `get_file` calls) don't each scan again.
+    /// Inserting files one by one only looks up a key or two in between changes, so it
+    /// doesn't keep rebuilding the index
+    fn find_file_index(&self, key: &str) -> Option<usize> {
+        if self.file_index.get().is_none() && self.scans.get() < SCANS_BEFORE_INDEX {
+            self.scans.set(self.scans.get() + 1);
+
+            return self
+                .chunks
+                .iter()
+                .position(|c| c.chunk_type.get_key() == Some(key));
         }
 
-        let chunk = PngChunk {
-            source: DataSource::Data(data),
-            chunk_type: ChunkType::File {
-                key: key.to_owned(),
-            },
-            crc,
-            len: len as u32,
+        let index = self.file_index.get_or_init(|| {
+            let mut index = HashMap::new();
+            for (idx, chunk) in self.chunks.iter().enumerate() {
+                if let Some(key) = chunk.chunk_type.get_key() {
+                    // first match wins, like the scan
+                    index.entry(key.to_owned()).or_insert(idx);
+                }
+            }
+
+            index
+        });
+
+        index.get(key).copied()
+    }
+
+    // the chunks, for changes that add, remove or move any, which invalidates the file index
+    fn chunks_mut(&mut self) -> &mut Vec<PngChunk> {
+        self.file_index.take();
+        self.scans.set(0);
+
+        &mut self.chunks
+    }
+
+    /// Returns the keys of all files matching `pred`, in the order they're stored
+    pub fn find_files<F: Fn(&str) -> bool>(&self, pred: F) -> Vec<&str> {
+        self.chunks
+            .iter()
+            .filter_map(|c| c.chunk_type.get_key())
+            .filter(|&key| pred(key))
+            .collect()
+    }
+
+    /// Returns the keys of all files matching the shell style glob `pattern`, in the
+    /// order they're stored
+    ///
+    /// `*` matches any run of characters and `?` any single one, `[a-z]` and `[!a-z]`
+    /// any character in or not in the class. None of them match `/`, so `*.json` only
+    /// matches at the top level; `**` matches across it, e.g. `assets/**/*.png`
+    pub fn keys_matching_glob(&self, pattern: &str) -> Vec<&str> {
+        self.find_files(|key| glob::matches(pattern, key))
+    }
+
+    /// Fully decode a file chunk, inflating its data
+    fn decode_chunk(&self, chunk: &PngChunk) -> Result<Vec<u8>, PngFilesError> {
+        self.decode_chunk_cow(chunk).map(Cow::into_owned)
+    }
+
+    // like decode_chunk, but stored files borrow from the chunk
+    fn decode_chunk_cow<'a>(&'a self, chunk: &'a PngChunk) -> Result<Cow<'a, [u8]>, PngFilesError> {
+        let (file, dictionary) = self.whole_file(chunk)?;
+        self.decode_file_data(file, dictionary)
+    }
+
+    // decode a whole file's data, with a registered decoder if it has a custom method
+    fn decode_file_data<'a>(
+        &self,
+        file: File<'a>,
+        dictionary: Option<&[u8]>,
+    ) -> Result<Cow<'a, [u8]>, PngFilesError> {
+        file.decode_data(self.max_decompressed, dictionary, &self.decoders)
+    }
+
+    // the file in a chunk with the data of all its parts, if it's split, and the preset
+    // dictionary it needs, if any
+    fn whole_file<'a>(
+        &'a self,
+        chunk: &'a PngChunk,
+    ) -> Result<(File<'a>, Option<&'a [u8]>), PngFilesError> {
+        let mut file = self
+            .chunk_file(chunk)
+            .map_err(|e| self.chunk_context(chunk, e))?;
+
+        let dictionary = match file.meta.dictionary {
+            Some(crc) => Some(self.find_dictionary(crc).ok_or(PngFilesError::Msg(
+                Cow::Borrowed("Dictionary for file not found"),
+            ))?),
+
+            None => None,
         };
 
-        // either insert or replace already existing key
-        if idx.is_none() {
-            self.chunks.push(chunk);
-        } else if let Some(idx) = idx {
-            let _ = std::mem::replace(&mut self.chunks[idx], chunk);
+        if file.meta.part.is_some() {
+            let mut data = file.data.into_owned();
+            for part in self.file_parts(&file.key, &file.meta)? {
+                data.extend_from_slice(&part);
+            }
+
+            file.data = Cow::Owned(data);
         }
 
-        Ok(())
+        Ok((file, dictionary))
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
-        // the capacity could be more, but at a minimum
-        let mut bytes = Vec::with_capacity(self.capacity);
+    // stored data of every part of a split file but the first, in order
+    // `meta` is the first part's
+    fn file_parts<'a>(
+        &'a self,
+        key: &str,
+        meta: &FileMeta,
+    ) -> Result<Vec<Cow<'a, [u8]>>, PngFilesError> {
+        let count = meta.part.map_or(1, |(_, count)| count);
+
+        let mut parts = self
+            .chunks
+            .iter()
+            .filter(|c| matches!(&c.chunk_type, ChunkType::FilePart { key: k } if k == key))
+            .map(|c| {
+                let file = self.chunk_file(c).map_err(|e| self.chunk_context(c, e))?;
+                Ok((file.meta.part.map(|(index, _)| index), file.data))
+            })
+            .collect::<Result<Vec<_>, PngFilesError>>()?;
+
+        parts.sort_by_key(|(index, _)| *index);
 
-        bytes.extend_from_slice(PNG_HEADER);
-        for chunk in self.chunks {
-            bytes.extend(chunk.into_bytes());
+        // a part that lost its chunk type would claim to be the first
+        let complete = parts.len() + 1 == count as usize
+            && meta.part.is_none_or(|(index, _)| index == 0)
+            && parts
+                .iter()
+                .enumerate()
+                .all(|(idx, (index, _))| *index == Some(idx as u32 + 1));
+
+        if !complete {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Parts of split file {key} are missing"
+            ))))?;
         }
 
-        bytes
+        Ok(parts.into_iter().map(|(_, data)| data).collect())
+    }
+
+    // size of a file's stored data, all parts of a split file included
+    fn stored_len(&self, chunk: &PngChunk) -> Result<usize, PngFilesError> {
+        let file = self.chunk_file(chunk)?;
+
+        let parts = match file.meta.part {
+            Some(_) => self.file_parts(&file.key, &file.meta)?,
+            None => Vec::new(),
+        };
+
+        Ok(file.data.len() + parts.iter().map(|p| p.len()).sum::<usize>())
+    }
+
+    // say which chunk a decode error came from
+    fn chunk_context(&self, chunk: &PngChunk, e: PngFilesError) -> PngFilesError {
+        let Some(index) = self.chunks.iter().position(|c| std::ptr::eq(c, chunk)) else {
+            return e;
+        };
+
+        let offset = match &chunk.source {
+            // chunk data starts after len + chunk type
+            DataSource::Range { range, .. } => Some(range.start - 8),
+            DataSource::Data(_) => None,
+        };
+
+        e.with_chunk(index, offset)
+    }
+
+    // the data of the dictionary chunk with this crc
+    fn find_dictionary(&self, crc: u32) -> Option<&[u8]> {
+        self.chunks
+            .iter()
+            .find(|c| c.chunk_type.as_ref() == DICT_CHUNK_TYPE && crc32fast::hash(c) == crc)
+            .map(|c| &**c)
+    }
+
+    /// Read just the key out of a `fiLe` chunk's data, without touching the file data
+    pub fn peek_key(chunk_data: &[u8]) -> Result<&str, PngFilesError> {
+        // the key is the first field, so decoding stops right after it
+        let (key, _) =
+            bincode::borrow_decode_from_slice::<&str, _>(chunk_data, bincode::config::standard())?;
+
+        Ok(key)
+    }
+
+    // the key of a packed file chunk, see PACKED_CHUNK_TYPE
+    // the key comes first, so only as much as the longest key and its length is inflated
+    fn peek_packed_key(chunk_data: &[u8]) -> Result<String, PngFilesError> {
+        let start = deflate::inflate_prefix(chunk_data, MAX_KEY_LEN + 3, None)?;
+
+        Ok(Self::peek_key(&start)?.to_owned())
+    }
+
+    // the file in a file chunk, inflating it first if it's packed
+    fn chunk_file<'a>(&self, chunk: &'a PngChunk) -> Result<File<'a>, PngFilesError> {
+        match chunk.chunk_type {
+            ChunkType::PackedFile { .. } => Self::decode_packed(chunk, self.max_decompressed),
+            _ => Self::decode_file(chunk),
+        }
+    }
+
+    // decode the data of a packed file chunk, see PACKED_CHUNK_TYPE
+    // `max` is the limit for the file's decoded data, which the stored data is never much
+    // bigger than, so the whole file is held to it plus room for the key and metadata
+    fn decode_packed(data: &[u8], max: usize) -> Result<File<'static>, PngFilesError> {
+        let plain = deflate::inflate(data, max.saturating_add(MAX_KEY_LEN + META_LIMIT), None)?;
+        let file = Self::decode_file(&plain)?;
+
+        Ok(File {
+            key: Cow::Owned(file.key.into_owned()),
+            data: Cow::Owned(file.data.into_owned()),
+            meta: file.meta,
+        })
+    }
+
+    // note: decoded file is NOT deflate decoded in order to allow for slice borrow
+    fn decode_file(data: &[u8]) -> Result<File<'_>, PngFilesError> {
+        let config = bincode::config::standard();
+
+        let ((key, file_data), read) =
+            bincode::borrow_decode_from_slice::<(&str, &[u8]), _>(data, config)?;
+
+        let meta = match &data[read..] {
+            [] => FileMeta::default(),
+
+            tail => match tail.strip_prefix(META_MAGIC) {
+                Some(meta) => Self::decode_meta(meta)?,
+                // anything else after the data isn't ours, so the chunk was corrupted or
+                // tampered with
+                None => Err(PngFilesError::TrailingBytes(tail.len()))?,
+            },
+        };
+
+        Ok(File {
+            key: Cow::Borrowed(key),
+            data: Cow::Borrowed(file_data),
+            meta,
+        })
+    }
+
+    // decode the metadata after META_MAGIC, which must be all that's left of the chunk
+    fn decode_meta(data: &[u8]) -> Result<FileMeta, PngFilesError> {
+        let config = bincode::config::standard();
+
+        let Some((&META_VERSION, data)) = data.split_first() else {
+            return Err(PngFilesError::Msg(Cow::Borrowed(
+                "Unsupported file metadata version",
+            )));
+        };
+
+        let (bytes, read) = bincode::borrow_decode_from_slice::<&[u8], _>(data, config)?;
+        if read < data.len() {
+            Err(PngFilesError::TrailingBytes(data.len() - read))?;
+        }
+
+        // the field count is read before any field, so a corrupt one would otherwise
+        // try to allocate however much it claims
+        let (fields, fields_read) = bincode::decode_from_slice::<Vec<MetaField>, _>(
+            bytes,
+            config.with_limit::<META_LIMIT>(),
+        )?;
+        if fields_read < bytes.len() {
+            Err(PngFilesError::Msg(Cow::Borrowed("Corrupt file metadata")))?;
+        }
+
+        Ok(FileMeta::from_fields(fields))
+    }
+
+    /// File data is encoded with `file.meta.method`; deflate optionally uses a preset dictionary,
+    /// the other methods ignore it
+    /// Returns its chunk, or the chunks of its parts if it's over `max_chunk_size`
+    fn encode_file(
+        &self,
+        mut file: File<'_>,
+        dictionary: Option<&[u8]>,
+        options: DeflateOptions,
+    ) -> Result<Vec<PngChunk>, PngFilesError> {
+        // every way of adding a file ends up here
+        Self::validate_key(&file.key)?;
+
+        if file.meta.method == CompressionMethod::Gzip {
+            let data = deflate::gzip(&file.data, Compression::new(options.level.min(9)))?;
+            file.data = Cow::Owned(data);
+        }
+
+        // stored data is kept as-is, so a dictionary is meaningless for it
+        if file.meta.method == CompressionMethod::Deflate {
+            let level = Compression::new(options.level.min(9));
+
+            // only the default strategy supports dictionaries, the others are never
+            // given one
+            let data = match options.strategy {
+                DeflateStrategy::Default => deflate::deflate(&file.data, level, dictionary)?,
+                strategy => deflate::deflate_with_strategy(&file.data, level, strategy)?,
+            };
+            file.data = Cow::Owned(data);
+            file.meta.dictionary = dictionary.map(crc32fast::hash);
+        }
+
+        if file.data.len() <= self.max_chunk_size {
+            let key = file.key.clone();
+            let data = Self::serialize_file(file)?;
+
+            return Ok(vec![self.file_chunk(&key, data, options.level)?]);
+        }
+
+        let parts = file.data.chunks(self.max_chunk_size);
+        let count = u32::try_from(parts.len())
+            .map_err(|_| PngFilesError::Msg(Cow::Borrowed("File has too many parts")))?;
+
+        parts
+            .enumerate()
+            .map(|(index, data)| {
+                let index = index as u32;
+
+                // only the first part carries the file's metadata
+                let meta = if index == 0 {
+                    FileMeta {
+                        part: Some((index, count)),
+                        ..file.meta.clone()
+                    }
+                } else {
+                    FileMeta {
+                        part: Some((index, count)),
+                        ..Default::default()
+                    }
+                };
+
+                let data = Self::serialize_file(File {
+                    key: Cow::Borrowed(&file.key),
+                    data: Cow::Borrowed(data),
+                    meta,
+                })?;
+
+                if index == 0 {
+                    PngChunk::new_file(&file.key, data, self.fast_mode)
+                } else {
+                    PngChunk::new_file_part(&file.key, data, self.fast_mode)
+                }
+            })
+            .collect()
+    }
+
+    // the chunk of a serialized file that isn't split, packed if set_deflate_keys is on
+    // and that makes it smaller
+    fn file_chunk(&self, key: &str, data: Vec<u8>, level: u32) -> Result<PngChunk, PngFilesError> {
+        if self.deflate_keys {
+            let packed = deflate::deflate(&data, Compression::new(level.min(9)), None)?;
+
+            if packed.len() < data.len() {
+                return PngChunk::new_packed_file(key, packed, self.fast_mode);
+            }
+        }
+
+        PngChunk::new_file(key, data, self.fast_mode)
+    }
+
+    /// Serialize a file whose data is already deflated
+    fn serialize_file(file: File<'_>) -> Result<Vec<u8>, PngFilesError> {
+        let config = bincode::config::standard();
+
+        let mut data = bincode::encode_to_vec((file.key, &*file.data), config)?;
+
+        let fields = file.meta.to_fields();
+        if !fields.is_empty() {
+            let fields = bincode::encode_to_vec(fields, config)?;
+
+            data.extend_from_slice(META_MAGIC);
+            data.push(META_VERSION);
+            data.extend(bincode::encode_to_vec(&*fields, config)?);
+        }
+
+        Ok(data)
+    }
+
+    /// Rename every file for which `f` returns a new key, leaving the rest untouched
+    /// Fails without renaming anything if the new keys would collide
+    /// Returns the number of files renamed
+    pub fn rename_all<F: Fn(&str) -> Option<String>>(
+        &mut self,
+        f: F,
+    ) -> Result<usize, PngFilesError> {
+        // (chunk index, new key) for every file that actually changes
+        let mut renames = Vec::new();
+        let mut keys = HashMap::new();
+
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            let Some(key) = chunk.chunk_type.get_key() else {
+                continue;
+            };
+
+            let new_key = match f(key) {
+                Some(new_key) if new_key != key => {
+                    Self::validate_key(&new_key)?;
+                    renames.push((idx, new_key.clone()));
+                    Cow::Owned(new_key)
+                }
+
+                _ => Cow::Borrowed(key),
+            };
+
+            // duplicates the image already has are left alone, only renames may not clash
+            let renamed = matches!(new_key, Cow::Owned(_));
+            if let Some((other, other_renamed)) = keys.insert(new_key, (key, renamed)) {
+                if renamed || other_renamed {
+                    Err(PngFilesError::Msg(Cow::Owned(format!(
+                        "Renaming {key} and {other} results in the same key"
+                    ))))?;
+                }
+            }
+        }
+
+        let renamed = renames.len();
+
+        // parts of split files follow their file
+        let new_keys = renames
+            .iter()
+            .map(|(idx, new_key)| (self.chunks[*idx].chunk_type.file_key(), new_key.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let renames = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| Some((idx, new_keys.get(&c.chunk_type.file_key())?.clone())))
+            .collect::<Vec<_>>();
+
+        for (idx, new_key) in &renames {
+            // the key lives inside the encoded file, so the chunk has to be rebuilt
+            // the data is reused as-is, no need to inflate and deflate it again
+            let chunk = &self.chunks[*idx];
+            let file = self.chunk_file(chunk)?;
+            let data = Self::serialize_file(File {
+                key: Cow::Borrowed(new_key),
+                data: file.data,
+                meta: file.meta,
+            })?;
+
+            self.chunks_mut()[*idx] = match chunk.chunk_type {
+                ChunkType::FilePart { .. } => {
+                    PngChunk::new_file_part(new_key, data, self.fast_mode)?
+                }
+                _ => self.file_chunk(new_key, data, self.deflate_options.level)?,
+            };
+        }
+
+        Ok(renamed)
+    }
+
+    /// Replace the `old` prefix of every key starting with it by `new`, e.g. to move all
+    /// files under `old/` to `new/`
+    /// Fails without renaming anything if a new key would collide with another key
+    /// Returns the number of files renamed
+    pub fn replace_key_prefix(&mut self, old: &str, new: &str) -> Result<usize, PngFilesError> {
+        self.rename_all(|key| key.strip_prefix(old).map(|rest| format!("{new}{rest}")))
+    }
+
+    /// Remove a file from png, returning whether one was removed or not
+    /// Use `take_file` to get the removed file's data back instead, e.g. to move it
+    pub fn remove_file(&mut self, key: &str) -> bool {
+        let idx = self.find_file_index(key);
+
+        if let Some(idx) = idx {
+            self.chunks_mut().remove(idx);
+            self.remove_parts(key);
+            self.remove_unused_dictionaries();
+            true
+        } else {
+            false
+        }
+    }
+
+    // remove the parts of a split file after its first
+    fn remove_parts(&mut self, key: &str) {
+        self.chunks_mut()
+            .retain(|c| !matches!(&c.chunk_type, ChunkType::FilePart { key: k } if k == key));
+    }
+
+    /// Keep only the files whose key satisfies `pred`, removing the rest
+    /// Image chunks are always kept. Returns how many files were removed
+    pub fn retain_files<F: FnMut(&str) -> bool>(&mut self, mut pred: F) -> usize {
+        let removed = self
+            .chunks
+            .iter()
+            .filter_map(|c| c.chunk_type.get_key())
+            .filter(|key| !pred(key))
+            .map(str::to_owned)
+            .collect::<HashSet<_>>();
+
+        let before = self.file_count();
+
+        // parts of split files go along with their file
+        self.chunks_mut().retain(|c| {
+            c.chunk_type
+                .file_key()
+                .is_none_or(|key| !removed.contains(key))
+        });
+        self.remove_unused_dictionaries();
+
+        before - self.file_count()
+    }
+
+    /// Remove every file, keeping only the image itself
+    /// Returns how many files were removed
+    pub fn clear_files(&mut self) -> usize {
+        let critical = self.critical_chunk_count();
+        let removed = self.retain_files(|_| false);
+
+        // the output wouldn't open anymore without them
+        debug_assert_eq!(
+            self.critical_chunk_count(),
+            critical,
+            "removing files removed IHDR or IEND"
+        );
+
+        removed
+    }
+
+    // how many IHDR and IEND chunks there are, which file operations must never change
+    fn critical_chunk_count(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|c| matches!(c.chunk_type.as_ref(), "IHDR" | "IEND"))
+            .count()
+    }
+
+    /// Remove every file whose `size` is over `bytes`, returning the removed keys
+    /// Files that fail to decode are kept
+    pub fn remove_files_larger_than(&mut self, bytes: usize, size: FileSize) -> Vec<String> {
+        let mut larger = self
+            .file_chunks()
+            .into_iter()
+            .filter_map(|(key, chunk)| {
+                let len = match size {
+                    FileSize::Compressed => self.stored_len(chunk).ok()?,
+                    FileSize::Decompressed => self.decode_chunk(chunk).ok()?.len(),
+                };
+
+                (len > bytes).then(|| key.to_owned())
+            })
+            .collect::<Vec<_>>();
+
+        // in storage order, file_chunks is unordered
+        larger.sort_by_key(|key| self.find_file_index(key));
+
+        let remove = larger.iter().map(String::as_str).collect::<HashSet<_>>();
+        self.retain_files(|key| !remove.contains(key));
+
+        larger
+    }
+
+    /// Remove a file from png, returning its (inflated) data
+    /// Returns none if the key was not found; the file is kept if it fails to decode
+    pub fn take_file(&mut self, key: &str) -> Result<Option<Vec<u8>>, PngFilesError> {
+        let Some(idx) = self.find_file_index(key) else {
+            return Ok(None);
+        };
+
+        let data = self.decode_chunk(&self.chunks[idx])?;
+        self.chunks_mut().remove(idx);
+        self.remove_parts(key);
+        self.remove_unused_dictionaries();
+
+        Ok(Some(data))
+    }
+
+    /// insert file chunk into PNG
+    /// `replace` overwrites existing key if it exists
+    ///
+    /// New files always go right before IEND, so after every file already embedded and
+    /// storage order stays insertion order. A replaced file keeps its place, only
+    /// `insert_file_at` puts a file anywhere else
+    pub fn insert_file(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        self.put_file(
+            key,
+            data,
+            None,
+            FileMeta::default(),
+            self.deflate_options,
+            replace,
+        )
+    }
+
+    /// Insert or replace a file, unless it's already stored with exactly this data
+    /// The stored file is inflated to compare it, which is still much cheaper than
+    /// deflating it again. Returns whether the file was written
+    pub fn set_file_if_changed(&mut self, key: &str, data: &[u8]) -> Result<bool, PngFilesError> {
+        let unchanged = self
+            .find_file_index(key)
+            .and_then(|idx| self.decode_chunk(&self.chunks[idx]).ok())
+            .is_some_and(|stored| stored == data);
+
+        if unchanged {
+            return Ok(false);
+        }
+
+        self.insert_file(key, data.to_vec(), true)?;
+
+        Ok(true)
+    }
+
+    /// insert a file under a key derived from its content, storing identical data only once
+    /// Returns the key, the hex FNV-1a hash of `data`, whether or not it was already stored
+    ///
+    /// The hash isn't cryptographic, so a stored file under the same key is compared to
+    /// `data` first; if they differ this fails instead of overwriting it
+    pub fn insert_deduped(&mut self, data: Vec<u8>) -> Result<String, PngFilesError> {
+        let key = format!("{:016x}", fnv1a(&data));
+
+        if let Some(idx) = self.find_file_index(&key) {
+            if *self.decode_chunk_cow(&self.chunks[idx])? != *data {
+                Err(PngFilesError::Msg(Cow::Owned(format!(
+                    "A different file is already stored under hash {key}"
+                ))))?;
+            }
+
+            return Ok(key);
+        }
+
+        self.insert_file(&key, data, false)?;
+
+        Ok(key)
+    }
+
+    /// insert file chunk into PNG, deflating it with `options` instead of the defaults
+    /// `replace` overwrites existing key if it exists
+    pub fn insert_file_with_options(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        options: DeflateOptions,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        self.put_file(key, data, None, FileMeta::default(), options, replace)
+    }
+
+    /// insert file chunk into PNG, storing its data with `method`
+    /// `replace` overwrites existing key if it exists
+    pub fn insert_file_with_method(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        method: CompressionMethod,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let meta = FileMeta {
+            method,
+            ..Default::default()
+        };
+
+        self.put_file(key, data, None, meta, self.deflate_options, replace)
+    }
+
+    /// insert a symlink into PNG, stored as a file holding its target path
+    /// The target is kept as-is, it is up to the reader to decide whether to follow it
+    /// `replace` overwrites existing key if it exists
+    pub fn insert_symlink(
+        &mut self,
+        key: &str,
+        target: &str,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let meta = FileMeta {
+            symlink: true,
+            ..Default::default()
+        };
+
+        self.put_file(
+            key,
+            target.as_bytes().to_vec(),
+            None,
+            meta,
+            self.deflate_options,
+            replace,
+        )
+    }
+
+    /// insert file chunk into PNG that `purge_expired` removes once `expires_at` has passed
+    /// `expires_at` is in whatever unit `purge_expired` is later called with, e.g. unix seconds
+    /// `replace` overwrites existing key if it exists
+    pub fn insert_file_with_expiry(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        expires_at: u64,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let meta = FileMeta {
+            expires_at: Some(expires_at),
+            ..Default::default()
+        };
+
+        self.put_file(key, data, None, meta, self.deflate_options, replace)
+    }
+
+    /// Remove every file whose expiry is at or before `now`
+    /// Files without an expiry, or that fail to decode, are kept. Returns how many were removed
+    pub fn purge_expired(&mut self, now: u64) -> usize {
+        let expired = self
+            .file_chunks()
+            .into_iter()
+            .filter(|(_, c)| {
+                self.chunk_file(c)
+                    .ok()
+                    .and_then(|f| f.meta.expires_at)
+                    .is_some_and(|time| time <= now)
+            })
+            .map(|(key, _)| key.to_owned())
+            .collect::<HashSet<_>>();
+
+        self.retain_files(|key| !expired.contains(key))
+    }
+
+    /// Replace a file's data, returning the previous (inflated) data
+    /// Returns none if the key didn't exist yet, in which case the file is inserted
+    pub fn swap_file(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, PngFilesError> {
+        let old = match self.find_file_index(key) {
+            Some(idx) => Some(self.decode_chunk(&self.chunks[idx])?),
+            None => None,
+        };
+
+        self.put_file(
+            key,
+            data,
+            None,
+            FileMeta::default(),
+            self.deflate_options,
+            true,
+        )?;
+
+        Ok(old)
+    }
+
+    /// Reserve room for at least `additional` more files, to avoid reallocating while
+    /// inserting many of them one by one
+    /// This is only a hint; inserting more or fewer files than reserved is fine
+    pub fn reserve_files(&mut self, additional: usize) {
+        self.chunks.reserve(additional);
+    }
+
+    /// insert several files into PNG
+    /// `replace` overwrites existing keys if they exist
+    pub fn insert_files<'a>(
+        &mut self,
+        files: impl IntoIterator<Item = (&'a str, Vec<u8>)>,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let files = files.into_iter();
+        self.reserve_files(files.size_hint().0);
+
+        for (key, data) in files {
+            self.insert_file(key, data, replace)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace every embedded file with exactly `files`, leaving image chunks untouched
+    /// All files are encoded before anything is removed, so on error the PNG is unchanged
+    /// Files are stored sorted by key, so the same set always serializes the same way
+    pub fn set_files(&mut self, files: HashMap<String, Vec<u8>>) -> Result<(), PngFilesError> {
+        let mut files = files.into_iter().collect::<Vec<_>>();
+        files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let chunks = files
+            .iter()
+            .map(|(key, data)| {
+                let file = File {
+                    key: Cow::Borrowed(key),
+                    data: Cow::Borrowed(data),
+                    meta: FileMeta::default(),
+                };
+
+                self.encode_file(file, None, self.deflate_options)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // removing files never moves IHDR or IEND, so if this fails it does before any change
+        self.chunk_position(FilePosition::BeforeIend)?;
+
+        self.clear_files();
+
+        let idx = self.chunk_position(FilePosition::BeforeIend)?;
+        self.chunks_mut()
+            .splice(idx..idx, chunks.into_iter().flatten());
+
+        Ok(())
+    }
+
+    /// insert several files into PNG, deflating them with a shared preset dictionary
+    /// Similar small files (e.g. json configs) compress much better against a dictionary of
+    /// their common content. The dictionary is stored in the PNG once, for any number of files
+    /// `replace` overwrites existing keys if they exist
+    ///
+    /// Keys are checked before anything is added, so a bad or taken key leaves the PNG
+    /// unchanged
+    pub fn insert_files_with_dictionary<'a>(
+        &mut self,
+        files: impl IntoIterator<Item = (&'a str, Vec<u8>)>,
+        dictionary: &[u8],
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let files = files.into_iter().collect::<Vec<_>>();
+
+        let mut keys = HashSet::new();
+        for (key, _) in &files {
+            Self::validate_key(key)?;
+            self.existing_file(key, replace)?;
+
+            if !replace && !keys.insert(*key) {
+                Err(PngFilesError::Msg(Cow::Borrowed("Key already in use")))?;
+            }
+        }
+
+        if self.find_dictionary(crc32fast::hash(dictionary)).is_none() {
+            let idx = self.chunk_position(FilePosition::BeforeIend)?;
+            let chunk = PngChunk::new(DICT_CHUNK_TYPE, dictionary.to_vec())?;
+            self.chunks_mut().insert(idx, chunk);
+        }
+
+        // dictionaries only work with the default strategy
+        let options = DeflateOptions {
+            strategy: DeflateStrategy::Default,
+            ..self.deflate_options
+        };
+
+        for (key, data) in files {
+            let result = self.put_file(
+                key,
+                data,
+                Some(dictionary),
+                FileMeta::default(),
+                options,
+                replace,
+            );
+
+            // the dictionary isn't left behind if no file made it in
+            if result.is_err() {
+                self.remove_unused_dictionaries();
+            }
+
+            result?;
+        }
+
+        Ok(())
+    }
+
+    // remove the dictionary chunks no file is deflated with anymore
+    // a file that fails to decode may still need any of them, so then they're all kept
+    fn remove_unused_dictionaries(&mut self) {
+        if self.chunk_index(DICT_CHUNK_TYPE).is_none() {
+            return;
+        }
+
+        let mut used = HashSet::new();
+        for chunk in self
+            .chunks
+            .iter()
+            .filter(|c| c.chunk_type.get_key().is_some())
+        {
+            match self.chunk_file(chunk) {
+                Ok(file) => used.extend(file.meta.dictionary),
+                Err(_) => return,
+            }
+        }
+
+        let unused = |c: &PngChunk| {
+            c.chunk_type.as_ref() == DICT_CHUNK_TYPE && !used.contains(&crc32fast::hash(c))
+        };
+
+        // chunks_mut drops the key index, so only when there's something to remove
+        if self.chunks.iter().any(unused) {
+            self.chunks_mut().retain(|c| !unused(c));
+        }
+    }
+
+    // encode file and either insert it, or replace the file with the same key
+    // `replace` overwrites existing key if it exists, otherwise it fails
+    fn put_file(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        dictionary: Option<&[u8]>,
+        meta: FileMeta,
+        options: DeflateOptions,
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        let idx = self.existing_file(key, replace)?;
+
+        let file = File {
+            key: Cow::Borrowed(key),
+            data: Cow::Borrowed(&data),
+            meta,
+        };
+
+        let chunks = self.encode_file(file, dictionary, options)?;
+
+        // either insert or replace already existing key
+        if idx.is_none() {
+            // new files always go before IEND, anything after it is ignored by decoders
+            let idx = self.chunk_position(FilePosition::BeforeIend)?;
+            self.chunks_mut().splice(idx..idx, chunks);
+        } else {
+            // the old file may have been split too, and dropping its parts can move it
+            self.remove_parts(key);
+            let idx = self.find_file_index(key).unwrap();
+            self.chunks_mut().splice(idx..=idx, chunks);
+
+            // the replaced file may have been the last one using a dictionary
+            self.remove_unused_dictionaries();
+        }
+
+        Ok(())
+    }
+
+    // index of the file with this key, failing if there is one and it may not be replaced
+    fn existing_file(&self, key: &str, replace: bool) -> Result<Option<usize>, PngFilesError> {
+        let idx = self.find_file_index(key);
+
+        // check that no key already exists in data
+        if !replace && idx.is_some() {
+            Err(PngFilesError::Msg(Cow::Borrowed("Key already in use")))?;
+        }
+
+        Ok(idx)
+    }
+
+    /// insert file chunk into PNG at a specific position
+    /// Fails if the key is already in use, or if the position is before IHDR or after IEND
+    pub fn insert_file_at(
+        &mut self,
+        key: &str,
+        data: Vec<u8>,
+        position: FilePosition,
+    ) -> Result<(), PngFilesError> {
+        self.existing_file(key, false)?;
+
+        let idx = self.chunk_position(position)?;
+
+        let file = File {
+            key: Cow::Borrowed(key),
+            data: Cow::Borrowed(&data),
+            meta: FileMeta::default(),
+        };
+
+        let chunks = self.encode_file(file, None, self.deflate_options)?;
+        self.chunks_mut().splice(idx..idx, chunks);
+
+        Ok(())
+    }
+
+    // resolve a position to the chunk index a new chunk would be inserted at
+    fn chunk_position(&self, position: FilePosition) -> Result<usize, PngFilesError> {
+        let ihdr = self.chunk_index("IHDR");
+        let iend = self.chunk_index("IEND");
+
+        let idx = match position {
+            FilePosition::BeforeIend => iend.unwrap_or(self.chunks.len()),
+
+            FilePosition::AfterIdat => {
+                self.chunks
+                    .iter()
+                    .rposition(|c| c.chunk_type.as_ref() == "IDAT")
+                    .ok_or(PngFilesError::Msg(Cow::Borrowed("Image has no IDAT chunk")))?
+                    + 1
+            }
+
+            FilePosition::AtIndex(idx) => idx,
+        };
+
+        let min = ihdr.map_or(0, |i| i + 1);
+        let max = iend.unwrap_or(self.chunks.len());
+
+        if idx < min || idx > max {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Position {idx} must be after IHDR and before IEND"
+            ))))?;
+        }
+
+        Ok(idx)
+    }
+
+    // index of the first chunk of a regular chunk type
+    fn chunk_index(&self, chunk_type: &str) -> Option<usize> {
+        self.chunks
+            .iter()
+            .position(|c| c.chunk_type.as_ref() == chunk_type)
+    }
+
+    /// Recalculate the crc of every chunk from its current type and data
+    pub fn recompute_crcs(&mut self) {
+        for chunk in &mut self.chunks {
+            chunk.crc = chunk.compute_crc();
+        }
+    }
+
+    /// Check every chunk's stored crc against its current type and data
+    pub fn verify_all_crcs(&self) -> Result<(), PngFilesError> {
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            if chunk.compute_crc() != chunk.crc {
+                Err(PngFilesError::Msg(Cow::Owned(format!(
+                    "Crc check failed for {} chunk at index {idx}",
+                    chunk.chunk_type.as_ref()
+                ))))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the image's pixel data, swapping all IDAT chunks for a single new one
+    /// `data` must be valid zlib compressed image data for the existing IHDR; it isn't checked
+    /// Embedded files and all other chunks are kept
+    pub fn set_image_data(&mut self, data: Vec<u8>) -> Result<(), PngFilesError> {
+        let Some(ihdr) = self.chunk_index("IHDR") else {
+            Err(PngFilesError::Msg(Cow::Borrowed("Image has no IHDR chunk")))?
+        };
+
+        let chunk = PngChunk::new("IDAT", data)?;
+
+        // the new IDAT takes the place of the first old one, or goes after IHDR (and PLTE)
+        let idx = match self.chunk_index("IDAT") {
+            Some(idx) => idx,
+            None => self.chunk_index("PLTE").unwrap_or(ihdr) + 1,
+        };
+
+        self.chunks_mut()
+            .retain(|c| c.chunk_type.as_ref() != "IDAT");
+        self.chunks_mut().insert(idx, chunk);
+
+        Ok(())
+    }
+
+    /// Inflate the image's pixel data and deflate it again at `level` (0-9), shrinking
+    /// the image without touching its pixels or the embedded files
+    ///
+    /// All IDAT chunks are merged into one, like `set_image_data`. The scanline filters
+    /// are kept as they are, only the compression changes. The result is only used if
+    /// it's smaller, returns whether it was
+    pub fn recompress_idat(&mut self, level: u32) -> Result<bool, PngFilesError> {
+        let idat = self
+            .chunks
+            .iter()
+            .filter(|c| c.chunk_type.as_ref() == "IDAT")
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect::<Vec<_>>();
+
+        if idat.is_empty() {
+            Err(PngFilesError::Msg(Cow::Borrowed("Image has no IDAT chunk")))?;
+        }
+
+        // pixel data is held to the same limit as embedded files
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(idat.as_slice())
+            .take((self.max_decompressed as u64).saturating_add(1))
+            .read_to_end(&mut pixels)?;
+
+        if pixels.len() > self.max_decompressed {
+            Err(PngFilesError::DecompressLimit(self.max_decompressed))?;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level.min(9)));
+        encoder.write_all(&pixels)?;
+        let recompressed = encoder.finish()?;
+
+        if recompressed.len() >= idat.len() {
+            return Ok(false);
+        }
+
+        self.set_image_data(recompressed)?;
+
+        Ok(true)
+    }
+
+    /// Bit depth of the image, from IHDR
+    pub fn bit_depth(&self) -> Option<u8> {
+        // width - 4, height - 4, then bit depth
+        self.ihdr().and_then(|c| c.get(8).copied())
+    }
+
+    /// Whether the image uses Adam7 interlacing, from IHDR
+    pub fn interlaced(&self) -> Option<bool> {
+        // width - 4, height - 4, bit depth, color type, compression, filter, then interlace
+        self.ihdr()
+            .and_then(|c| c.get(12))
+            .map(|&method| method != 0)
+    }
+
+    fn ihdr(&self) -> Option<&PngChunk> {
+        self.chunks.iter().find(|c| c.chunk_type.as_ref() == "IHDR")
+    }
+
+    /// The PNG signature followed by the serialized IHDR chunk
+    /// Returns an empty vec if the image has no IHDR chunk
+    pub fn header_prefix(&self) -> Vec<u8> {
+        let Some(ihdr) = self.ihdr() else {
+            return Vec::new();
+        };
+
+        let mut bytes = Vec::with_capacity(self.signature.len() + 4 + 4 + ihdr.len() + 4);
+        bytes.extend_from_slice(&self.signature);
+        bytes.extend(ihdr.to_bytes());
+
+        bytes
+    }
+
+    /// Copy every chunk that still refers into the parsed input into its own allocation,
+    /// so no chunk shares the input buffer anymore
+    ///
+    /// The input buffer is freed once this returns (unless it's an `Arc` also held
+    /// elsewhere), which is worth it when only a small part of a big input is kept
+    pub fn owned_only(mut self) -> Self {
+        for chunk in &mut self.chunks {
+            if let DataSource::Range { .. } = chunk.source {
+                chunk.source = DataSource::Data(chunk.to_vec());
+            }
+        }
+
+        self
+    }
+
+    /// Approximate number of bytes of chunk data this `Png` keeps in memory
+    ///
+    /// Owned chunk data (inserted files, new chunks) is counted as allocated. A shared input
+    /// buffer is counted once, in full, if any chunk still refers to it, since the whole
+    /// buffer stays alive until no chunk does
+    pub fn memory_usage(&self) -> usize {
+        let mut buffers = HashSet::new();
+
+        self.chunks
+            .iter()
+            .map(|c| match &c.source {
+                DataSource::Data(data) => data.capacity(),
+                DataSource::Range { data, .. } => {
+                    if buffers.insert(data.as_ptr()) {
+                        data.len()
+                    } else {
+                        0
+                    }
+                }
+            })
+            .sum()
+    }
+
+    /// Exact size in bytes `into_bytes` would produce
+    /// Always reflects the files inserted and removed so far, e.g. to show the current
+    /// output size
+    pub fn serialized_len(&self) -> usize {
+        let comment = self.auto_comment();
+
+        self.signature.len()
+            + self
+                .output_chunks(comment.as_ref())
+                // len + type + data + crc
+                .map(|c| 4 + 4 + c.len() + 4)
+                .sum::<usize>()
+    }
+
+    /// Size in bytes `into_bytes` would produce after inserting (or replacing) a file,
+    /// without inserting it. The file is deflated at `level` to get its exact size, so this
+    /// costs as much as inserting it
+    pub fn projected_size_with(
+        &self,
+        key: &str,
+        data: &[u8],
+        level: Compression,
+    ) -> Result<usize, PngFilesError> {
+        let file = File {
+            key: Cow::Borrowed(key),
+            data: Cow::Borrowed(data),
+            meta: FileMeta::default(),
+        };
+
+        let options = DeflateOptions {
+            level: level.level(),
+            ..self.deflate_options
+        };
+
+        // len + type + data + crc
+        let chunk_len = |c: &PngChunk| 4 + 4 + c.len() + 4;
+
+        let new_len = self
+            .encode_file(file, None, options)?
+            .iter()
+            .map(chunk_len)
+            .sum::<usize>();
+
+        let (old_len, count) = match self.find_file_index(key) {
+            Some(_) => (
+                self.chunks
+                    .iter()
+                    .filter(|c| c.chunk_type.file_key() == Some(key))
+                    .map(chunk_len)
+                    .sum(),
+                self.file_count(),
+            ),
+            None => (0, self.file_count() + 1),
+        };
+
+        // the auto comment spells out the file count, which may gain a digit
+        let comment_growth = if self.auto_comment {
+            count.to_string().len() - self.file_count().to_string().len()
+        } else {
+            0
+        };
+
+        Ok(self.serialized_len() - old_len + new_len + comment_growth)
+    }
+
+    /// Report how much the embedded files inflate the serialized PNG
+    /// Every part of a split file, and every dictionary, counts towards the files' size
+    pub fn overhead(&self) -> Overhead {
+        let total_len = self.serialized_len();
+        let files_len = self
+            .chunks
+            .iter()
+            .filter(|c| {
+                c.chunk_type.file_key().is_some() || c.chunk_type.as_ref() == DICT_CHUNK_TYPE
+            })
+            .map(|c| 4 + 4 + c.len() + 4)
+            .sum::<usize>();
+        let image_len = total_len - files_len;
+
+        Overhead {
+            image_len,
+            total_len,
+            percent: files_len as f64 / image_len as f64 * 100.0,
+        }
+    }
+
+    /// Write every embedded file into a tar archive, keyed by its key
+    /// Parent directories of path-style keys (`dir/file`) get their own directory entries
+    #[cfg(feature = "tar")]
+    pub fn export_tar<W: Write>(&self, writer: W) -> Result<(), PngFilesError> {
+        let mut builder = tar::Builder::new(writer);
+        let mut dirs = std::collections::HashSet::new();
+
+        for chunk in &self.chunks {
+            let Some(key) = chunk.chunk_type.get_key() else {
+                continue;
+            };
+
+            // "a/b/c" implies the directories "a/" and "a/b/"
+            for (idx, _) in key.match_indices('/') {
+                let dir = &key[..=idx];
+
+                if dirs.insert(dir) {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+
+                    builder.append_data(&mut header, dir, std::io::empty())?;
+                }
+            }
+
+            // decompressed per entry so only one file is held in memory at a time
+            let data = self.decode_chunk(chunk)?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+
+            builder.append_data(&mut header, key, &*data)?;
+        }
+
+        builder.into_inner()?;
+
+        Ok(())
+    }
+
+    /// Embed every regular file in a tar archive, using the entry path as the key
+    /// Directories, symlinks and other special entries are skipped
+    /// Fails on an entry bigger than the max decompressed size, since it couldn't be read back
+    /// `replace` overwrites existing keys if they exist
+    #[cfg(feature = "tar")]
+    pub fn import_tar<R: Read>(&mut self, reader: R, replace: bool) -> Result<(), PngFilesError> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let key = entry
+                .path()?
+                .to_str()
+                .ok_or(PngFilesError::Msg(Cow::Borrowed(
+                    "Tar entry path is not valid utf-8",
+                )))?
+                .to_owned();
+
+            // the size in the header is whatever the archive claims, so it's neither trusted
+            // for allocating up front nor allowed past what a file may inflate to
+            let mut data = Vec::new();
+            (&mut entry)
+                .take((self.max_decompressed as u64).saturating_add(1))
+                .read_to_end(&mut data)?;
+
+            if data.len() > self.max_decompressed {
+                Err(PngFilesError::DecompressLimit(self.max_decompressed))?;
+            }
+
+            self.insert_file(&key, data, replace)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a chunk survives serialization under the current policy
+    fn keeps_chunk(&self, chunk: &PngChunk) -> bool {
+        let chunk_type = &chunk.chunk_type;
+
+        match self.unknown_chunk_policy {
+            UnknownChunkPolicy::KeepAll => true,
+            UnknownChunkPolicy::DropUnsafeAncillary => {
+                chunk_type.is_known() || !chunk_type.is_ancillary() || chunk_type.is_safe_to_copy()
+            }
+        }
+    }
+
+    /// Serialize the PNG into `writer` chunk by chunk, without buffering the whole file
+    /// Returns the number of bytes written
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<usize, PngFilesError> {
+        writer.write_all(&self.signature)?;
+        let mut written = self.signature.len();
+
+        let comment = self.auto_comment();
+        for chunk in self.output_chunks(comment.as_ref()) {
+            written += chunk.write_to(&mut writer)?;
+        }
+
+        writer.flush()?;
+
+        Ok(written)
+    }
+
+    /// Serialize the PNG to a file at `path`, overwriting it if it exists
+    /// Returns the number of bytes written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<usize, PngFilesError> {
+        let path = path.as_ref();
+
+        let file =
+            fs::File::create(path).map_err(|e| PngFilesError::from(e).with_path("create", path))?;
+
+        self.write_to(BufWriter::new(file))
+            .map_err(|e| e.with_path("write", path))
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.serialized_len());
+
+        bytes.extend_from_slice(&self.signature);
+
+        let comment = self.auto_comment();
+        for chunk in self.output_chunks(comment.as_ref()) {
+            bytes.extend(chunk.to_bytes());
+        }
+
+        bytes
+    }
+
+    /// Serialize the PNG like `into_bytes`, dropping each chunk as soon as it's written
+    ///
+    /// Inserted files are freed one by one instead of all at the end, lowering peak memory
+    /// when many large files were added. The parsed input buffer is still freed only once
+    /// the last chunk referring to it is written
+    pub fn compact_to_bytes(mut self) -> Vec<u8> {
+        // exact, so the output never reallocates while chunks are still alive
+        let mut bytes = Vec::with_capacity(self.serialized_len());
+        bytes.extend_from_slice(&self.signature);
+
+        let comment = self.auto_comment();
+        let chunks = std::mem::take(&mut self.chunks);
+
+        for chunk in chunks {
+            if !self.writes_chunk(&chunk, comment.is_some()) {
+                continue;
+            }
+
+            if chunk.chunk_type.as_ref() == "IEND" {
+                if let Some(comment) = &comment {
+                    comment.extend_bytes(&mut bytes);
+                }
+            }
+
+            chunk.extend_bytes(&mut bytes);
+        }
+
+        bytes
+    }
+
+    // the chunks that actually get serialized, in order
+    // `comment` is the auto comment chunk (if enabled), which goes right before IEND
+    fn output_chunks<'a>(
+        &'a self,
+        comment: Option<&'a PngChunk>,
+    ) -> impl Iterator<Item = &'a PngChunk> {
+        self.chunks
+            .iter()
+            .filter(move |c| self.writes_chunk(c, comment.is_some()))
+            .flat_map(move |c| {
+                let comment = comment.filter(|_| c.chunk_type.as_ref() == "IEND");
+                comment.into_iter().chain(std::iter::once(c))
+            })
+    }
+
+    // whether a chunk gets serialized at all
+    fn writes_chunk(&self, chunk: &PngChunk, has_comment: bool) -> bool {
+        // a fresh comment replaces the stale one
+        self.keeps_chunk(chunk) && !(has_comment && chunk.is_auto_comment())
+    }
+
+    /// Serialize the PNG one piece at a time: the PNG signature first, then each chunk
+    /// Useful to drive serialization from e.g. an async writer; the concatenated pieces are
+    /// exactly what `into_bytes` returns
+    pub fn chunk_bytes(self) -> impl Iterator<Item = Vec<u8>> {
+        let signature = self.signature.to_vec();
+        let chunks = self.into_output_chunks().map(|c| c.to_bytes());
+
+        std::iter::once(signature).chain(chunks)
+    }
+
+    /// Serialize the PNG lazily as it's read, e.g. to stream it to an http client
+    /// The bytes read are exactly what `into_bytes` returns
+    pub fn into_reader(self) -> impl Read {
+        PngReader::new(self.signature, self.into_output_chunks())
+    }
+
+    // owning version of output_chunks
+    fn into_output_chunks(self) -> impl Iterator<Item = PngChunk> {
+        let mut comment = self.auto_comment();
+
+        // decided up front, since the iterator has to own the chunks
+        let writes = self
+            .chunks
+            .iter()
+            .map(|c| self.writes_chunk(c, comment.is_some()))
+            .collect::<Vec<_>>();
+
+        self.chunks
+            .into_iter()
+            .zip(writes)
+            .filter(|(_, writes)| *writes)
+            .flat_map(move |(c, _)| {
+                let comment = if c.chunk_type.as_ref() == "IEND" {
+                    comment.take()
+                } else {
+                    None
+                };
+
+                comment.into_iter().chain(std::iter::once(c))
+            })
+    }
+
+    // build the auto comment chunk describing the current embedded files, if enabled
+    fn auto_comment(&self) -> Option<PngChunk> {
+        if !self.auto_comment {
+            return None;
+        }
+
+        let text = format!(
+            "{AUTO_COMMENT_PREFIX}{}{AUTO_COMMENT_SUFFIX}",
+            self.file_count()
+        );
+
+        PngChunk::new("tEXt", text.into_bytes()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank() -> Png {
+        Png::new_blank(2, 2).unwrap()
+    }
+
+    // write and parse again, so everything is read back the way it is from disk
+    fn reparse(png: Png) -> Png {
+        Png::new(png.into_bytes()).unwrap()
+    }
+
+    // a png whose only file chunk has `junk` appended after what `png` stored for it
+    fn with_junk(mut png: Png, junk: &[u8]) -> Png {
+        let idx = png.find_file_index("a").unwrap();
+
+        let mut data = png.chunks[idx].to_vec();
+        data.extend_from_slice(junk);
+        png.chunks_mut()[idx] = PngChunk::new_file("a", data, false).unwrap();
+
+        reparse(png)
+    }
+
+    #[test]
+    fn trailing_junk_is_rejected() {
+        for junk in [&[0][..], &[1, 1], b"meTa", b"meTa\x01\x00"] {
+            let mut png = blank();
+            png.insert_file("a", b"hello".to_vec(), false).unwrap();
+
+            let png = with_junk(png, junk);
+
+            assert!(png.get_file("a").is_none(), "junk {junk:?} was accepted");
+            assert!(png.validate_files()[0].1.is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_junk_after_metadata_is_rejected() {
+        let mut png = blank();
+        png.insert_file_with_method("a", b"hello".to_vec(), CompressionMethod::Store, false)
+            .unwrap();
+
+        let png = with_junk(png, &[0]);
+
+        assert!(matches!(
+            Png::decode_file(&png.chunks[png.find_file_index("a").unwrap()]),
+            Err(PngFilesError::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn files_without_junk_decode() {
+        let mut png = blank();
+        png.insert_file("plain", b"hello".to_vec(), false).unwrap();
+        png.insert_file_with_method("stored", b"hello".to_vec(), CompressionMethod::Store, false)
+            .unwrap();
+
+        let png = reparse(png);
+
+        assert_eq!(png.get_file("plain").unwrap(), b"hello");
+        assert_eq!(png.get_file("stored").unwrap(), b"hello");
+        assert!(png.validate_files().iter().all(|(_, r)| r.is_ok()));
+    }
+
+    #[test]
+    fn embedded_png_is_detected() {
+        let mut inner = blank();
+        inner.insert_file("b.txt", b"b".to_vec(), false).unwrap();
+
+        let mut png = blank();
+        png.insert_file("inner.png", inner.into_bytes(), false)
+            .unwrap();
+        png.insert_file("a.txt", b"a".to_vec(), false).unwrap();
+
+        assert!(png.is_embedded_png("inner.png"));
+        assert!(!png.is_embedded_png("a.txt"));
+        assert!(!png.is_embedded_png("missing"));
+
+        let files = png.extract_all_recursive().unwrap();
+        assert_eq!(files["inner.png/b.txt"], b"b");
+        assert_eq!(files["a.txt"], b"a");
+        assert_eq!(files.len(), 3);
+    }
+
+    #[cfg(feature = "tar")]
+    fn tar_entry(path: &str, size: u64, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_size(size);
+        header.set_cksum();
+
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.extend_from_slice(data);
+        bytes.resize(bytes.len().next_multiple_of(512), 0);
+        bytes
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn import_tar_ignores_claimed_size() {
+        // claims 8 GiB but holds a few bytes
+        let archive = tar_entry("huge.bin", (8 << 30) - 1, b"tiny");
+
+        assert!(blank().import_tar(&*archive, false).is_err());
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn import_tar_limits_entry_size() {
+        let archive = tar_entry("big.bin", 32, &[7; 32]);
+
+        let mut png = blank().with_max_decompressed(16);
+        assert!(matches!(
+            png.import_tar(&*archive, false),
+            Err(PngFilesError::DecompressLimit(16))
+        ));
+
+        let mut png = blank().with_max_decompressed(32);
+        png.import_tar(&*archive, false).unwrap();
+        assert_eq!(png.get_file("big.bin").unwrap(), [7; 32]);
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn import_tar_without_limit() {
+        let archive = tar_entry("a.bin", 32, &[7; 32]);
+
+        let mut png = blank().with_max_decompressed(usize::MAX);
+        png.import_tar(&*archive, false).unwrap();
+        assert_eq!(png.get_file("a.bin").unwrap(), [7; 32]);
+    }
+
+    // data that deflate can't shrink, so it splits into a predictable number of parts
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_file_round_trips() {
+        let data = noise(1000);
+
+        let mut png = blank().with_max_chunk_size(100);
+        png.insert_file("split", data.clone(), false).unwrap();
+        png.insert_file("small", b"small".to_vec(), false).unwrap();
+
+        let png = reparse(png);
+
+        let parts = png
+            .chunks
+            .iter()
+            .filter(|c| c.chunk_type.as_ref() == PART_CHUNK_TYPE);
+        assert!(parts.count() > 5);
+
+        assert_eq!(png.files_in_order(), ["split", "small"]);
+        assert_eq!(png.get_file("split").unwrap(), data);
+        assert_eq!(png.get_file("small").unwrap(), b"small");
+    }
+
+    #[test]
+    fn split_file_with_missing_part_fails() {
+        let mut png = blank().with_max_chunk_size(100);
+        png.insert_file("split", noise(1000), false).unwrap();
+
+        let idx = png
+            .chunks
+            .iter()
+            .position(|c| c.chunk_type.as_ref() == PART_CHUNK_TYPE)
+            .unwrap();
+        png.chunks_mut().remove(idx);
+
+        assert!(png.get_file("split").is_none());
+    }
+
+    #[test]
+    fn overhead_counts_every_part() {
+        let image_len = blank().serialized_len();
+
+        let mut png = blank().with_max_chunk_size(100);
+        png.insert_file("split", noise(1000), false).unwrap();
+
+        let overhead = png.overhead();
+        assert_eq!(overhead.image_len, image_len);
+        assert_eq!(overhead.total_len, png.serialized_len());
+    }
+
+    #[test]
+    fn overhead_counts_dictionaries() {
+        let image_len = blank().serialized_len();
+
+        let mut png = blank();
+        png.insert_files_with_dictionary([("a", b"{}".to_vec())], b"dictionary", false)
+            .unwrap();
+
+        assert_eq!(png.overhead().image_len, image_len);
+    }
+
+    fn dictionary_count(png: &Png) -> usize {
+        png.chunks
+            .iter()
+            .filter(|c| c.chunk_type.as_ref() == DICT_CHUNK_TYPE)
+            .count()
+    }
+
+    const CONFIG: &[u8] = br#"{"name": "example", "enabled": true, "retries": 3}"#;
+
+    #[test]
+    fn dictionary_round_trips() {
+        let mut png = blank();
+        png.insert_files_with_dictionary(
+            [
+                ("a.json", CONFIG.to_vec()),
+                ("b.json", CONFIG[..20].to_vec()),
+            ],
+            CONFIG,
+            false,
+        )
+        .unwrap();
+        png.insert_file("plain.json", CONFIG.to_vec(), false)
+            .unwrap();
+
+        let png = reparse(png);
+
+        assert_eq!(dictionary_count(&png), 1);
+        assert_eq!(png.get_file("a.json").unwrap(), CONFIG);
+        assert_eq!(png.get_file("b.json").unwrap(), &CONFIG[..20]);
+        assert_eq!(png.get_file("plain.json").unwrap(), CONFIG);
+
+        // deflating against a dictionary that holds the whole file leaves almost nothing
+        let with = png.file_info("a.json").unwrap().compressed_len;
+        let without = png.file_info("plain.json").unwrap().compressed_len;
+        assert!(with < without);
+    }
+
+    #[test]
+    fn dictionary_insert_checks_keys_first() {
+        let mut png = blank();
+        png.insert_file("taken", b"taken".to_vec(), false).unwrap();
+        let before = png.chunks.len();
+
+        for files in [
+            vec![("new", CONFIG.to_vec()), ("taken", CONFIG.to_vec())],
+            vec![("new", CONFIG.to_vec()), ("new", CONFIG.to_vec())],
+            vec![("new", CONFIG.to_vec()), ("", CONFIG.to_vec())],
+        ] {
+            assert!(png
+                .insert_files_with_dictionary(files, CONFIG, false)
+                .is_err());
+            assert_eq!(png.chunks.len(), before);
+        }
+    }
+
+    #[test]
+    fn unused_dictionaries_are_removed() {
+        let files = || [("a", CONFIG.to_vec()), ("b", CONFIG.to_vec())];
+
+        let mut png = blank();
+        png.insert_files_with_dictionary(files(), CONFIG, false)
+            .unwrap();
+
+        png.remove_file("a");
+        assert_eq!(dictionary_count(&png), 1);
+        png.take_file("b").unwrap();
+        assert_eq!(dictionary_count(&png), 0);
+
+        png.insert_files_with_dictionary(files(), CONFIG, false)
+            .unwrap();
+        png.insert_file("a", CONFIG.to_vec(), true).unwrap();
+        png.retain_files(|key| key != "b");
+        assert_eq!(dictionary_count(&png), 0);
+
+        png.insert_files_with_dictionary(files(), CONFIG, true)
+            .unwrap();
+        png.clear_files();
+        assert_eq!(dictionary_count(&png), 0);
+
+        png.insert_files_with_dictionary(files(), CONFIG, false)
+            .unwrap();
+        png.set_files(HashMap::from([("c".to_owned(), CONFIG.to_vec())]))
+            .unwrap();
+        assert_eq!(dictionary_count(&png), 0);
+        assert_eq!(png.get_file("c").unwrap(), CONFIG);
+    }
+
+    fn chunk_types(png: &Png) -> Vec<&str> {
+        png.chunks
+            .iter()
+            .filter(|c| c.chunk_type.file_key().is_some())
+            .map(|c| c.chunk_type.as_ref())
+            .collect()
+    }
+
+    // a path whose directory names repeat, which deflating the key as well pays off for
+    fn long_key() -> String {
+        format!("{}file.json", "assets/config/".repeat(20))
+    }
+
+    #[test]
+    fn deflated_keys_round_trip() {
+        let key = long_key();
+
+        let mut png = blank().with_deflate_keys(true);
+        png.insert_file(&key, CONFIG.to_vec(), false).unwrap();
+        png.insert_file("noise", noise(64), false).unwrap();
+
+        // nothing in it repeats, so that file isn't deflated again
+        assert_eq!(chunk_types(&png), [PACKED_CHUNK_TYPE, CHUNK_TYPE]);
+
+        // the setting isn't stored in the image, reading packed files works without it
+        let mut png = reparse(png).with_deflate_keys(true);
+
+        assert_eq!(png.files_in_order(), [key.as_str(), "noise"]);
+        assert_eq!(png.get_file(&key).unwrap(), CONFIG);
+        assert_eq!(png.get_file("noise").unwrap(), noise(64));
+        assert!(png.validate_files().iter().all(|(_, r)| r.is_ok()));
+
+        png.rename_all(|k| (k == key).then(|| format!("{key}.old")))
+            .unwrap();
+        let png = reparse(png);
+        assert_eq!(png.get_file(&format!("{key}.old")).unwrap(), CONFIG);
+        assert_eq!(chunk_types(&png), [PACKED_CHUNK_TYPE, CHUNK_TYPE]);
+
+        let recovered = Png::recover_files(&png.into_bytes());
+        assert_eq!(recovered[0], (format!("{key}.old"), CONFIG.to_vec()));
+    }
+
+    #[test]
+    fn deflated_keys_shrink_repetitive_keys() {
+        let key = long_key();
+
+        let mut plain = blank();
+        plain.insert_file(&key, CONFIG.to_vec(), false).unwrap();
+
+        let mut packed = blank().with_deflate_keys(true);
+        packed.insert_file(&key, CONFIG.to_vec(), false).unwrap();
+
+        assert!(packed.serialized_len() + key.len() / 2 < plain.serialized_len());
+    }
+
+    #[test]
+    fn deflated_keys_skip_split_files() {
+        let mut png = blank().with_deflate_keys(true).with_max_chunk_size(100);
+        png.insert_file(&long_key(), noise(300), false).unwrap();
+
+        assert!(!chunk_types(&png).contains(&PACKED_CHUNK_TYPE));
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        // a megabyte of zeroes deflates to about a kilobyte
+        let mut png = blank();
+        png.insert_file("bomb", vec![0; 1 << 20], false).unwrap();
+
+        let mut png = reparse(png).with_max_decompressed(1024);
+        let chunk = &png.chunks[png.find_file_index("bomb").unwrap()];
+
+        assert!(matches!(
+            png.decode_chunk(chunk),
+            Err(PngFilesError::DecompressLimit(1024))
+        ));
+        assert!(png.get_file("bomb").is_none());
+
+        png.set_max_decompressed(1 << 20);
+        assert_eq!(png.get_file("bomb").unwrap().len(), 1 << 20);
+    }
+
+    #[test]
+    fn short_input_is_not_png() {
+        for data in [vec![0x89, b'P'], vec![1, 2, 3], vec![0; 100]] {
+            let len = data.len();
+
+            assert!(
+                matches!(
+                    Png::new(data),
+                    Err(PngFilesError::Msg(msg)) if msg == "Input file is not PNG format"
+                ),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_length_chunks_round_trip() {
+        let mut png = blank();
+        let iend = png.chunks.len() - 1;
+        png.chunks_mut()
+            .insert(iend, PngChunk::new("prIv", Vec::new()).unwrap());
+        png.insert_file("empty", Vec::new(), false).unwrap();
+
+        let bytes = png.into_bytes();
+        let png = Png::new(bytes.clone()).unwrap();
+
+        let chunks = png.iter_png_chunks().collect::<Vec<_>>();
+        assert_eq!(chunks[chunks.len() - 2], ("prIv", &[][..]));
+        assert_eq!(chunks[chunks.len() - 1], ("IEND", &[][..]));
+
+        assert_eq!(png.get_file("empty").unwrap(), b"");
+        assert_eq!(png.into_bytes(), bytes);
+    }
+
+    #[test]
+    fn rename_all_strips_prefix() {
+        let mut png = blank();
+        for key in ["build/a.txt", "build/b/c.txt", "readme"] {
+            png.insert_file(key, key.as_bytes().to_vec(), false)
+                .unwrap();
+        }
+
+        let renamed = png
+            .rename_all(|key| key.strip_prefix("build/").map(str::to_owned))
+            .unwrap();
+        assert_eq!(renamed, 2);
+
+        let png = reparse(png);
+        assert_eq!(png.files_in_order(), ["a.txt", "b/c.txt", "readme"]);
+        assert_eq!(png.get_file("b/c.txt").unwrap(), b"build/b/c.txt");
+    }
+
+    #[test]
+    fn rename_all_rejects_collisions() {
+        let mut png = blank();
+        png.insert_file("build/a", b"1".to_vec(), false).unwrap();
+        png.insert_file("a", b"2".to_vec(), false).unwrap();
+
+        assert!(png
+            .rename_all(|key| key.strip_prefix("build/").map(str::to_owned))
+            .is_err());
+        assert_eq!(png.files_in_order(), ["build/a", "a"]);
+    }
+
+    #[test]
+    fn rename_all_ignores_existing_duplicates() {
+        let mut png = blank();
+        png.insert_file("a", b"1".to_vec(), false).unwrap();
+        png.insert_file("b", b"2".to_vec(), false).unwrap();
+        // a second "a", like a writer that didn't check for duplicates would leave
+        let duplicate = PngChunk::new_file("a", png.chunks[2].to_vec(), false).unwrap();
+        png.chunks_mut().insert(4, duplicate);
+        assert_eq!(png.files_in_order(), ["a", "b", "a"]);
+
+        let renamed = png
+            .rename_all(|key| (key == "b").then(|| "c".to_owned()))
+            .unwrap();
+        assert_eq!(renamed, 1);
+        assert_eq!(png.files_in_order(), ["a", "c", "a"]);
+
+        // renaming onto a key that is already duplicated still clashes
+        assert!(png
+            .rename_all(|key| (key == "c").then(|| "a".to_owned()))
+            .is_err());
+        // as does renaming both copies to the same new key
+        assert!(png
+            .rename_all(|key| (key == "a").then(|| "d".to_owned()))
+            .is_err());
+    }
+
+    fn all_chunk_types(png: &Png) -> Vec<&str> {
+        png.chunks.iter().map(|c| c.chunk_type.as_ref()).collect()
+    }
+
+    #[test]
+    fn insert_file_at_positions() {
+        let mut png = blank();
+        png.chunks_mut()
+            .insert(2, PngChunk::new("prIv", Vec::new()).unwrap());
+
+        png.insert_file_at("end", b"1".to_vec(), FilePosition::BeforeIend)
+            .unwrap();
+        png.insert_file_at("idat", b"2".to_vec(), FilePosition::AfterIdat)
+            .unwrap();
+        png.insert_file_at("first", b"3".to_vec(), FilePosition::AtIndex(1))
+            .unwrap();
+
+        let png = reparse(png);
+        assert_eq!(
+            all_chunk_types(&png),
+            ["IHDR", "fiLe", "IDAT", "fiLe", "prIv", "fiLe", "IEND"]
+        );
+        assert_eq!(png.files_in_order(), ["first", "idat", "end"]);
+        assert_eq!(png.get_file("idat").unwrap(), b"2");
+    }
+
+    #[test]
+    fn insert_file_at_stays_between_ihdr_and_iend() {
+        let mut png = blank();
+        let len = png.chunks.len();
+
+        for idx in [0, len, len + 1] {
+            assert!(png
+                .insert_file_at("a", b"a".to_vec(), FilePosition::AtIndex(idx))
+                .is_err());
+        }
+        assert_eq!(png.file_count(), 0);
+
+        png.insert_file_at("a", b"a".to_vec(), FilePosition::AtIndex(len - 1))
+            .unwrap();
+        assert_eq!(all_chunk_types(&png), ["IHDR", "IDAT", "fiLe", "IEND"]);
+    }
+
+    #[test]
+    fn huge_chunk_length_is_rejected() {
+        // signature, then IHDR with its 13 bytes of data, then the IDAT length
+        let mut bytes = blank().into_bytes();
+        bytes[33..37].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let Err(PngFilesError::Msg(msg)) = Png::new(bytes) else {
+            panic!("a chunk longer than the input was accepted");
+        };
+
+        // only 32-bit targets can overflow, elsewhere the data just isn't there
+        if cfg!(target_pointer_width = "32") {
+            assert_eq!(msg, "Chunk length overflows address space");
+        } else {
+            assert_eq!(msg, "Invalid chunk (type or data missing)");
+        }
+    }
+
+    #[test]
+    fn compression_method_is_reported() {
+        let methods = [
+            CompressionMethod::Deflate,
+            CompressionMethod::Store,
+            CompressionMethod::Gzip,
+            CompressionMethod::Custom(7),
+        ];
+
+        let mut png = blank();
+        for method in methods {
+            png.insert_file_with_method(&format!("{method:?}"), b"data".to_vec(), method, false)
+                .unwrap();
+        }
+
+        // written before files carried a method: just the key and deflated data
+        let deflated = deflate::deflate(b"data", Compression::best(), None).unwrap();
+        let legacy =
+            bincode::encode_to_vec(("legacy", &*deflated), bincode::config::standard()).unwrap();
+        let iend = png.chunks.len() - 1;
+        png.chunks_mut()
+            .insert(iend, PngChunk::new_file("legacy", legacy, false).unwrap());
+
+        let mut png = reparse(png);
+        png.register_decoder(7, |data, _| Ok(data.to_vec()));
+
+        for method in methods {
+            let key = format!("{method:?}");
+            assert_eq!(png.compression_method(&key), Some(method));
+            assert_eq!(png.get_file(&key).unwrap(), b"data");
+        }
+
+        assert_eq!(
+            png.compression_method("legacy"),
+            Some(CompressionMethod::Deflate)
+        );
+        assert_eq!(png.get_file("legacy").unwrap(), b"data");
+        assert_eq!(png.compression_method("missing"), None);
+    }
+
+    #[test]
+    fn purge_expired_keeps_live_files() {
+        let mut png = blank();
+        png.insert_file_with_expiry("old", b"1".to_vec(), 100, false)
+            .unwrap();
+        png.insert_file_with_expiry("now", b"2".to_vec(), 200, false)
+            .unwrap();
+        png.insert_file_with_expiry("later", b"3".to_vec(), 300, false)
+            .unwrap();
+        png.insert_file("forever", b"4".to_vec(), false).unwrap();
+
+        assert!(png
+            .insert_file_with_expiry("old", b"5".to_vec(), 100, false)
+            .is_err());
+
+        let mut png = reparse(png);
+        assert_eq!(png.purge_expired(200), 2);
+        assert_eq!(png.files_in_order(), ["later", "forever"]);
+
+        assert_eq!(png.purge_expired(u64::MAX), 1);
+        assert_eq!(png.files_in_order(), ["forever"]);
+    }
+
+    #[test]
+    fn rle_strategy_round_trips() {
+        let options = DeflateOptions {
+            level: 9,
+            strategy: DeflateStrategy::Rle,
+        };
+
+        let mut png = blank();
+        png.insert_file_with_options("rle", vec![7; 4096], options, false)
+            .unwrap();
+
+        let png = reparse(png);
+        assert_eq!(png.get_file("rle").unwrap(), vec![7; 4096]);
+        assert!(png.file_info("rle").unwrap().compressed_len < 100);
+    }
+
+    #[test]
+    fn parse_all_reads_concatenated_pngs() {
+        let mut first = blank();
+        first.insert_file("a", b"first".to_vec(), false).unwrap();
+        let mut second = blank();
+        second.insert_file("b", b"second".to_vec(), false).unwrap();
+
+        let mut data = first.into_bytes();
+        data.extend_from_slice(b"junk between images");
+        data.extend(second.into_bytes());
+
+        let pngs = Png::parse_all(data).unwrap();
+        assert_eq!(pngs.len(), 2);
+        assert_eq!(pngs[0].get_file("a").unwrap(), b"first");
+        assert_eq!(pngs[1].get_file("b").unwrap(), b"second");
+        assert!(!pngs[0].contains_file("b"));
+    }
+
+    #[test]
+    fn parse_all_reads_a_single_png() {
+        let mut png = blank();
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+
+        let pngs = Png::parse_all(png.into_bytes()).unwrap();
+        assert_eq!(pngs.len(), 1);
+        assert_eq!(pngs[0].get_file("a").unwrap(), b"a");
+
+        assert!(Png::parse_all(b"not a png".to_vec()).is_err());
+    }
+
+    #[test]
+    fn tree_groups_by_directory() {
+        let mut png = blank();
+        for key in ["readme", "src/main.rs", "src/lib.rs", "src/png/glob.rs"] {
+            png.insert_file_with_method(
+                key,
+                key.as_bytes().to_vec(),
+                CompressionMethod::Store,
+                false,
+            )
+            .unwrap();
+        }
+
+        let tree = png.tree();
+        assert_eq!(tree.keys().collect::<Vec<_>>(), ["", "src", "src/png"]);
+        assert_eq!(tree[""], [("readme".to_owned(), 6)]);
+        assert_eq!(
+            tree["src"],
+            [("lib.rs".to_owned(), 10), ("main.rs".to_owned(), 11)]
+        );
+        assert_eq!(tree["src/png"], [("glob.rs".to_owned(), 15)]);
+    }
+
+    #[test]
+    fn histogram_counts_every_chunk() {
+        let mut png = blank();
+        for _ in 0..2 {
+            png.chunks_mut()
+                .insert(2, PngChunk::new("IDAT", Vec::new()).unwrap());
+        }
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        png.insert_file("b", b"b".to_vec(), false).unwrap();
+
+        let histogram = reparse(png).chunk_type_histogram();
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            [
+                ("IDAT".to_owned(), 3),
+                ("IEND".to_owned(), 1),
+                ("IHDR".to_owned(), 1),
+                ("fiLe".to_owned(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_by_index_follow_removals() {
+        let mut png = blank();
+        for key in ["a", "b", "c", "d"] {
+            png.insert_file(key, key.as_bytes().to_vec(), false)
+                .unwrap();
+        }
+
+        png.remove_file("b");
+        png.insert_file("e", b"e".to_vec(), false).unwrap();
+        png.remove_file("a");
+
+        assert_eq!(png.file_count(), 3);
+        for (idx, key) in ["c", "d", "e"].into_iter().enumerate() {
+            assert_eq!(png.file_key_at(idx), Some(key));
+            assert_eq!(png.get_file_by_index(idx).unwrap(), key.as_bytes());
+        }
+
+        assert_eq!(png.file_key_at(3), None);
+        assert_eq!(png.get_file_by_index(3), None);
+    }
+
+    #[test]
+    fn chunk_types_must_be_4_bytes() {
+        for chunk_type in ["", "IDA", "IDATA", "fiL\u{e9}"] {
+            assert!(ChunkType::png(chunk_type).is_err(), "{chunk_type:?}");
+            assert!(PngChunk::new(chunk_type, Vec::new()).is_err());
+        }
+
+        assert!(ChunkType::png("prIv").is_ok());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn bad_chunk_type_is_not_written() {
+        let mut chunk = PngChunk::new("prIv", Vec::new()).unwrap();
+        chunk.chunk_type = ChunkType::Png("toolong".to_owned());
+
+        chunk.to_bytes();
+    }
+
+    #[test]
+    fn storage_order_is_insertion_order() {
+        let mut png = blank();
+        for key in ["a", "b", "c"] {
+            png.insert_file(key, b"1".to_vec(), false).unwrap();
+        }
+
+        png.remove_file("b");
+        png.insert_file("b", b"2".to_vec(), false).unwrap();
+        assert_eq!(png.files_in_order(), ["a", "c", "b"]);
+
+        // replacing keeps the file where it was
+        png.insert_file("a", b"2".to_vec(), true).unwrap();
+        png.insert_file("d", b"1".to_vec(), false).unwrap();
+
+        let png = reparse(png);
+        assert_eq!(png.files_in_order(), ["a", "c", "b", "d"]);
+        assert_eq!(png.get_file("a").unwrap(), b"2");
+    }
+
+    #[test]
+    fn payload_digest_ignores_the_image() {
+        let mut png = blank();
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        png.insert_file("b", b"b".to_vec(), false).unwrap();
+        let digest = png.payload_digest();
+
+        png.set_phys(1, 1, PhysUnit::Unknown).unwrap();
+        png.chunks_mut()
+            .insert(1, PngChunk::new("prIv", b"edit".to_vec()).unwrap());
+        png.recompress_idat(0).unwrap();
+        assert_eq!(reparse(png).payload_digest(), digest);
+
+        // the same files inserted the other way around, in fast mode
+        let mut other = blank().with_fast_mode(true);
+        other.insert_file("b", b"b".to_vec(), false).unwrap();
+        other.insert_file("a", b"a".to_vec(), false).unwrap();
+        assert_eq!(other.payload_digest(), digest);
+
+        other.insert_file("a", b"changed".to_vec(), true).unwrap();
+        assert_ne!(other.payload_digest(), digest);
+        other.remove_file("a");
+        assert_ne!(other.payload_digest(), digest);
+    }
+
+    #[test]
+    fn replace_key_prefix_moves_directory() {
+        let mut png = blank().with_max_chunk_size(64);
+        png.insert_file("old/a", b"a".to_vec(), false).unwrap();
+        png.insert_file("old/sub/b", noise(500), false).unwrap();
+        png.insert_file("older", b"c".to_vec(), false).unwrap();
+        png.insert_file("keep/old/d", b"d".to_vec(), false).unwrap();
+
+        assert_eq!(png.replace_key_prefix("old/", "new/").unwrap(), 2);
+
+        let mut png = reparse(png);
+        assert_eq!(
+            png.files_in_order(),
+            ["new/a", "new/sub/b", "older", "keep/old/d"]
+        );
+        // every part of a split file moves along
+        assert_eq!(png.get_file("new/sub/b").unwrap(), noise(500));
+
+        png.insert_file("x/a", b"x".to_vec(), false).unwrap();
+        assert!(png.replace_key_prefix("new/", "x/").is_err());
+        assert_eq!(png.replace_key_prefix("missing/", "x/").unwrap(), 0);
+    }
+
+    #[test]
+    fn keys_are_validated() {
+        let longest = "k".repeat(MAX_KEY_LEN);
+        for key in ["a", "dir/file.txt", "\u{e9}t\u{e9}", longest.as_str()] {
+            assert!(Png::validate_key(key).is_ok(), "{key:?}");
+        }
+
+        let too_long = "k".repeat(MAX_KEY_LEN + 1);
+        for key in ["", "a\nb", "a\0", "\u{7f}", "tab\t", too_long.as_str()] {
+            assert!(Png::validate_key(key).is_err(), "{key:?}");
+
+            // inserting applies the same rules
+            let mut png = blank();
+            assert!(png.insert_file(key, b"a".to_vec(), false).is_err());
+            assert_eq!(png.file_count(), 0);
+        }
+    }
+
+    // the inflated pixel data of every IDAT chunk
+    fn pixels(png: &Png) -> Vec<u8> {
+        let idat = png
+            .iter_png_chunks()
+            .filter(|(t, _)| *t == "IDAT")
+            .flat_map(|(_, data)| data)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(idat.as_slice())
+            .read_to_end(&mut pixels)
+            .unwrap();
+        pixels
+    }
+
+    #[test]
+    fn recompressed_idat_keeps_pixels() {
+        // a 64x64 gradient, stored uncompressed and split over several IDAT chunks
+        let mut png = Png::new_blank(64, 64).unwrap();
+        let rows = (0..64u8)
+            .flat_map(|y| std::iter::once(0).chain((0..64).map(move |x| x ^ y)))
+            .collect::<Vec<_>>();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::none());
+        encoder.write_all(&rows).unwrap();
+        png.set_image_data(encoder.finish().unwrap()).unwrap();
+
+        let idat = png.chunk_index("IDAT").unwrap();
+        let stored = png.chunks_mut().remove(idat).to_vec();
+        for part in stored.chunks(1000).rev() {
+            png.chunks_mut()
+                .insert(idat, PngChunk::new("IDAT", part.to_vec()).unwrap());
+        }
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        assert_eq!(pixels(&png), rows);
+
+        assert!(png.recompress_idat(9).unwrap());
+        assert!(!png.recompress_idat(0).unwrap());
+
+        let png = reparse(png);
+        assert_eq!(pixels(&png), rows);
+        assert_eq!(png.chunk_type_histogram()["IDAT"], 1);
+        assert_eq!(png.get_file("a").unwrap(), b"a");
+        assert!(png.validate_structure().is_empty());
+    }
+
+    #[test]
+    fn png_chunks_skip_files() {
+        let mut png = blank().with_max_chunk_size(64);
+        png.insert_file("a", noise(300), false).unwrap();
+        png.insert_files_with_dictionary([("b", CONFIG.to_vec())], CONFIG, false)
+            .unwrap();
+        png.set_time(ModificationTime {
+            year: 2000,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        })
+        .unwrap();
+
+        let png = reparse(png);
+        let types = png.iter_png_chunks().map(|(t, _)| t).collect::<Vec<_>>();
+        assert_eq!(types, ["IHDR", "IDAT", "tIME", "IEND"]);
+
+        let (_, ihdr) = png.iter_png_chunks().next().unwrap();
+        assert_eq!(ihdr[..8], [0, 0, 0, 2, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn gzip_raw_data_is_a_gzip_stream() {
+        let mut png = blank();
+        png.insert_file_with_method("a.css", CONFIG.to_vec(), CompressionMethod::Gzip, false)
+            .unwrap();
+
+        let png = reparse(png);
+        assert_eq!(png.get_file("a.css").unwrap(), CONFIG);
+
+        let raw = png.get_file_raw("a.css").unwrap();
+        // magic, deflate, then the trailer: crc and size of the uncompressed data
+        assert_eq!(raw[..3], [0x1f, 0x8b, 8]);
+        let (_, trailer) = raw.split_at(raw.len() - 8);
+        assert_eq!(trailer[..4], crc32fast::hash(CONFIG).to_le_bytes());
+        assert_eq!(trailer[4..], (CONFIG.len() as u32).to_le_bytes());
+
+        let mut gunzipped = Vec::new();
+        flate2::read::GzDecoder::new(&*raw)
+            .read_to_end(&mut gunzipped)
+            .unwrap();
+        assert_eq!(gunzipped, CONFIG);
+    }
+
+    fn assert_iend_last(png: &Png) {
+        let types = all_chunk_types(png);
+        assert_eq!(types.first(), Some(&"IHDR"));
+        assert_eq!(types.last(), Some(&"IEND"));
+        assert_eq!(png.critical_chunk_count(), 2);
+    }
+
+    #[test]
+    fn file_operations_keep_iend_last() {
+        let mut png = blank().with_max_chunk_size(64);
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        png.insert_file("split", noise(300), false).unwrap();
+        assert_iend_last(&png);
+
+        // keys shaped like the image's own chunk types are just keys
+        for key in ["IEND", "IHDR"] {
+            png.insert_file(key, b"x".to_vec(), false).unwrap();
+            assert_iend_last(&png);
+            assert!(png.remove_file(key));
+            assert!(!png.remove_file(key));
+            assert_iend_last(&png);
+        }
+
+        png.retain_files(|key| key == "a");
+        assert_iend_last(&png);
+
+        png.set_files(HashMap::from([("b".to_owned(), b"b".to_vec())]))
+            .unwrap();
+        assert_iend_last(&png);
+        assert_eq!(png.files_in_order(), ["b"]);
+
+        assert_eq!(png.clear_files(), 1);
+        assert_iend_last(&png);
+        assert_eq!(all_chunk_types(&reparse(png)), ["IHDR", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn files_go_before_iend_even_with_chunks_after_it() {
+        // some writers leave junk chunks past IEND
+        let mut png = blank();
+        png.chunks_mut()
+            .push(PngChunk::new("prIv", Vec::new()).unwrap());
+
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        assert_eq!(
+            all_chunk_types(&png),
+            ["IHDR", "IDAT", "fiLe", "IEND", "prIv"]
+        );
+    }
+
+    #[test]
+    fn file_prefix_is_exactly_max_bytes() {
+        let big = noise(200_000);
+
+        let mut png = blank().with_max_chunk_size(4096);
+        for method in [
+            CompressionMethod::Deflate,
+            CompressionMethod::Store,
+            CompressionMethod::Gzip,
+        ] {
+            png.insert_file_with_method(&format!("{method:?}"), big.clone(), method, false)
+                .unwrap();
+        }
+        png.insert_files_with_dictionary([("dict", big.clone())], &big[..1000], false)
+            .unwrap();
+        png.insert_file("short", b"short".to_vec(), false).unwrap();
+
+        let png = reparse(png);
+        for key in ["Deflate", "Store", "Gzip", "dict"] {
+            for len in [0, 1, 8, 100_000] {
+                let prefix = png.get_file_prefix(key, len).unwrap().unwrap();
+                assert_eq!(prefix, big[..len], "{key} at {len}");
+            }
+        }
+
+        assert_eq!(
+            png.get_file_prefix("short", 100).unwrap().unwrap(),
+            b"short"
+        );
+        assert_eq!(png.get_file_prefix("missing", 100).unwrap(), None);
+    }
+
+    #[test]
+    fn deduped_files_are_stored_once() {
+        let mut png = blank();
+
+        let key = png.insert_deduped(CONFIG.to_vec()).unwrap();
+        assert_eq!(png.insert_deduped(CONFIG.to_vec()).unwrap(), key);
+        assert_eq!(key.len(), 16);
+        assert_eq!(png.file_count(), 1);
+        assert_eq!(png.chunk_type_histogram()["fiLe"], 1);
+
+        let other = png.insert_deduped(b"other".to_vec()).unwrap();
+        assert_ne!(other, key);
+        assert_eq!(png.files_in_order(), [key.as_str(), other.as_str()]);
+        assert_eq!(png.get_file(&key).unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn deduped_key_clash_is_rejected() {
+        let mut png = blank();
+        let key = format!("{:016x}", fnv1a(CONFIG));
+        png.insert_file(&key, b"not the config".to_vec(), false)
+            .unwrap();
+
+        assert!(png.insert_deduped(CONFIG.to_vec()).is_err());
+        assert_eq!(png.get_file(&key).unwrap(), b"not the config");
+    }
+
+    #[test]
+    fn registered_decoder_reads_custom_files() {
+        // a trivial codec, storing the data reversed
+        let mut png = blank();
+        png.insert_file_with_method("a", b"olleh".to_vec(), CompressionMethod::Custom(1), false)
+            .unwrap();
+
+        let mut png = reparse(png);
+        assert!(png.get_file("a").is_none());
+        assert!(png.validate_files()[0].1.is_err());
+
+        png.register_decoder(1, |data, max| {
+            if data.len() > max {
+                Err(PngFilesError::DecompressLimit(max))?;
+            }
+
+            Ok(data.iter().rev().copied().collect())
+        });
+        assert_eq!(png.get_file("a").unwrap(), b"hello");
+        assert_eq!(png.get_file_prefix("a", 2).unwrap().unwrap(), b"he");
+        // stored as given
+        assert_eq!(&*png.get_file_raw("a").unwrap(), b"olleh");
+
+        png.set_max_decompressed(4);
+        assert!(png.get_file("a").is_none());
+    }
+
+    #[test]
+    fn recompressed_idat_without_limit_keeps_pixels() {
+        let mut png = Png::new_blank(64, 64)
+            .unwrap()
+            .with_max_decompressed(usize::MAX);
+        let before = pixels(&png);
+
+        png.recompress_idat(0).unwrap();
+        png.recompress_idat(9).unwrap();
+
+        assert_eq!(pixels(&reparse(png)), before);
+        assert_eq!(before.len(), 64 * 65);
     }
 }
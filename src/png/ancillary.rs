@@ -0,0 +1,349 @@
+use std::borrow::Cow;
+
+use super::{FilePosition, Png, PngChunk};
+use crate::PngFilesError;
+
+/// Unit of the pixel densities in a pHYs chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysUnit {
+    /// Only the aspect ratio is known
+    Unknown,
+    /// Pixels per meter
+    Meter,
+}
+
+/// Intended pixel size or aspect ratio, from the pHYs chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    /// Pixels per unit, x axis
+    pub x_ppu: u32,
+    /// Pixels per unit, y axis
+    pub y_ppu: u32,
+    pub unit: PhysUnit,
+}
+
+/// Background color to present the image against, from the bKGD chunk
+///
+/// Which variant applies depends on the image's color type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    /// Index into the palette (color type 3)
+    Palette(u8),
+    /// Gray level (color types 0 and 4)
+    Gray(u16),
+    /// Red, green and blue levels (color types 2 and 6)
+    Rgb(u16, u16, u16),
+}
+
+/// Time of the last image modification (UTC), from the tIME chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModificationTime {
+    pub year: u16,
+    /// 1 - 12
+    pub month: u8,
+    /// 1 - 31
+    pub day: u8,
+    /// 0 - 23
+    pub hour: u8,
+    /// 0 - 59
+    pub minute: u8,
+    /// 0 - 60 (for leap seconds)
+    pub second: u8,
+}
+
+// chunks that can tell who made the image, or when and where
+const METADATA_CHUNKS: [&str; 5] = ["tEXt", "zTXt", "iTXt", "tIME", "eXIf"];
+
+// where a new ancillary chunk is allowed to go
+enum Placement {
+    // before the first IDAT (and so after PLTE)
+    BeforeIdat,
+    // anywhere, so just before IEND
+    BeforeIend,
+}
+
+impl Png {
+    /// Physical pixel dimensions from the pHYs chunk
+    /// Returns none if there is no pHYs chunk or it is malformed
+    pub fn phys(&self) -> Option<PhysicalDimensions> {
+        let data = self.ancillary_chunk("pHYs")?;
+        if data.len() != 9 {
+            return None;
+        }
+
+        let unit = match data[8] {
+            0 => PhysUnit::Unknown,
+            1 => PhysUnit::Meter,
+            _ => return None,
+        };
+
+        Some(PhysicalDimensions {
+            x_ppu: u32::from_be_bytes(data[0..4].try_into().ok()?),
+            y_ppu: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            unit,
+        })
+    }
+
+    /// Set the physical pixel dimensions, replacing any existing pHYs chunk
+    pub fn set_phys(
+        &mut self,
+        x_ppu: u32,
+        y_ppu: u32,
+        unit: PhysUnit,
+    ) -> Result<(), PngFilesError> {
+        // the spec limits every four byte unsigned integer to 2^31 - 1
+        if x_ppu > i32::MAX as u32 || y_ppu > i32::MAX as u32 {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "Pixels per unit cannot be bigger than 2^31 - 1",
+            )))?;
+        }
+
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&x_ppu.to_be_bytes());
+        data.extend_from_slice(&y_ppu.to_be_bytes());
+        data.push(match unit {
+            PhysUnit::Unknown => 0,
+            PhysUnit::Meter => 1,
+        });
+
+        self.set_ancillary_chunk("pHYs", data, Placement::BeforeIdat)
+    }
+
+    /// Background color from the bKGD chunk
+    /// Returns none if there is no bKGD chunk or it is malformed
+    pub fn background(&self) -> Option<Background> {
+        let data = self.ancillary_chunk("bKGD")?;
+        let sample =
+            |idx: usize| Some(u16::from_be_bytes(data.get(idx..idx + 2)?.try_into().ok()?));
+
+        // the layout is fixed by the color type, so its length tells which one it is
+        match data.len() {
+            1 => Some(Background::Palette(data[0])),
+            2 => Some(Background::Gray(sample(0)?)),
+            6 => Some(Background::Rgb(sample(0)?, sample(2)?, sample(4)?)),
+            _ => None,
+        }
+    }
+
+    /// Set the background color, replacing any existing bKGD chunk
+    /// Fails if the variant doesn't match the image's color type
+    pub fn set_background(&mut self, background: Background) -> Result<(), PngFilesError> {
+        // width - 4, height - 4, bit depth, then color type
+        let color_type = self.ihdr().and_then(|c| c.get(9).copied());
+
+        let data = match (background, color_type) {
+            (Background::Palette(idx), Some(3)) => vec![idx],
+            (Background::Gray(gray), Some(0 | 4)) => gray.to_be_bytes().to_vec(),
+            (Background::Rgb(r, g, b), Some(2 | 6)) => {
+                [r, g, b].iter().flat_map(|c| c.to_be_bytes()).collect()
+            }
+
+            _ => Err(PngFilesError::Msg(Cow::Borrowed(
+                "Background does not match the image's color type",
+            )))?,
+        };
+
+        self.set_ancillary_chunk("bKGD", data, Placement::BeforeIdat)
+    }
+
+    /// Last modification time from the tIME chunk
+    /// Returns none if there is no tIME chunk or it is malformed
+    pub fn time(&self) -> Option<ModificationTime> {
+        let data = self.ancillary_chunk("tIME")?;
+        if data.len() != 7 {
+            return None;
+        }
+
+        let time = ModificationTime {
+            year: u16::from_be_bytes([data[0], data[1]]),
+            month: data[2],
+            day: data[3],
+            hour: data[4],
+            minute: data[5],
+            second: data[6],
+        };
+
+        time.is_valid().then_some(time)
+    }
+
+    /// Set the last modification time, replacing any existing tIME chunk
+    pub fn set_time(&mut self, time: ModificationTime) -> Result<(), PngFilesError> {
+        if !time.is_valid() {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "Invalid modification time",
+            )))?;
+        }
+
+        let mut data = Vec::with_capacity(7);
+        data.extend_from_slice(&time.year.to_be_bytes());
+        data.extend_from_slice(&[time.month, time.day, time.hour, time.minute, time.second]);
+
+        self.set_ancillary_chunk("tIME", data, Placement::BeforeIend)
+    }
+
+    /// Remove text (tEXt, zTXt, iTXt), modification time (tIME) and Exif (eXIf) chunks,
+    /// e.g. before sharing an image. The pixels, embedded files and everything else are kept
+    /// Returns how many chunks were removed
+    ///
+    /// The comment added by `set_auto_comment` is written on output regardless
+    pub fn strip_metadata(&mut self) -> usize {
+        let before = self.chunks.len();
+        self.chunks_mut()
+            .retain(|c| !METADATA_CHUNKS.contains(&c.chunk_type.as_ref()));
+
+        before - self.chunks.len()
+    }
+
+    // data of the first chunk of this type
+    fn ancillary_chunk(&self, chunk_type: &str) -> Option<&[u8]> {
+        self.chunk_index(chunk_type).map(|idx| &*self.chunks[idx])
+    }
+
+    // replace the chunk of this type in place, or insert it where it's allowed to go
+    fn set_ancillary_chunk(
+        &mut self,
+        chunk_type: &str,
+        data: Vec<u8>,
+        placement: Placement,
+    ) -> Result<(), PngFilesError> {
+        let chunk = PngChunk::new(chunk_type, data)?;
+
+        // these chunks may only appear once
+        if let Some(idx) = self.chunk_index(chunk_type) {
+            self.chunks_mut()[idx] = chunk;
+            return Ok(());
+        }
+
+        let idx = match placement {
+            Placement::BeforeIdat => self
+                .chunk_index("IDAT")
+                .ok_or(PngFilesError::Msg(Cow::Borrowed("Image has no IDAT chunk")))?,
+
+            Placement::BeforeIend => self.chunk_position(FilePosition::BeforeIend)?,
+        };
+
+        self.chunks_mut().insert(idx, chunk);
+
+        Ok(())
+    }
+}
+
+impl ModificationTime {
+    fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour <= 23
+            && self.minute <= 59
+            && self.second <= 60
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_types(png: &Png) -> Vec<&str> {
+        png.chunks.iter().map(|c| c.chunk_type.as_ref()).collect()
+    }
+
+    // write and parse again, which also checks the crcs
+    fn reparse(png: Png) -> Png {
+        Png::new(png.into_bytes()).unwrap()
+    }
+
+    #[test]
+    fn phys_round_trips() {
+        let mut png = Png::new_blank(1, 1).unwrap();
+        assert_eq!(png.phys(), None);
+
+        png.set_phys(2835, 2835, PhysUnit::Meter).unwrap();
+        png.set_phys(3780, 2835, PhysUnit::Meter).unwrap();
+
+        let png = reparse(png);
+        assert_eq!(chunk_types(&png), ["IHDR", "pHYs", "IDAT", "IEND"]);
+        assert_eq!(
+            png.phys(),
+            Some(PhysicalDimensions {
+                x_ppu: 3780,
+                y_ppu: 2835,
+                unit: PhysUnit::Meter,
+            })
+        );
+    }
+
+    #[test]
+    fn phys_rejects_out_of_range() {
+        let mut png = Png::new_blank(1, 1).unwrap();
+        assert!(png.set_phys(u32::MAX, 1, PhysUnit::Unknown).is_err());
+    }
+
+    #[test]
+    fn background_round_trips() {
+        // blank images are grayscale
+        let mut png = Png::new_blank(1, 1).unwrap();
+        assert!(png.set_background(Background::Rgb(1, 2, 3)).is_err());
+        assert!(png.set_background(Background::Palette(1)).is_err());
+
+        png.set_background(Background::Gray(1000)).unwrap();
+
+        let png = reparse(png);
+        assert_eq!(chunk_types(&png), ["IHDR", "bKGD", "IDAT", "IEND"]);
+        assert_eq!(png.background(), Some(Background::Gray(1000)));
+    }
+
+    #[test]
+    fn time_round_trips() {
+        let time = ModificationTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 60,
+        };
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.set_time(time).unwrap();
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+
+        let mut png = reparse(png);
+        assert_eq!(chunk_types(&png), ["IHDR", "IDAT", "tIME", "fiLe", "IEND"]);
+        assert_eq!(png.time(), Some(time));
+
+        assert!(png
+            .set_time(ModificationTime { month: 13, ..time })
+            .is_err());
+        assert_eq!(png.strip_metadata(), 1);
+        assert_eq!(png.time(), None);
+    }
+
+    #[test]
+    fn strip_metadata_keeps_image_and_files() {
+        let mut png = Png::new_blank(2, 2).unwrap();
+        let idat = png.ancillary_chunk("IDAT").unwrap().to_vec();
+
+        for (idx, chunk_type) in [(1, "gAMA"), (2, "eXIf"), (3, "tEXt"), (4, "iTXt")] {
+            let chunk = PngChunk::new(chunk_type, b"data".to_vec()).unwrap();
+            png.chunks_mut().insert(idx, chunk);
+        }
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+        png.set_time(ModificationTime {
+            year: 2000,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        })
+        .unwrap();
+
+        assert_eq!(png.strip_metadata(), 4);
+        assert_eq!(png.strip_metadata(), 0);
+
+        let png = reparse(png);
+        assert_eq!(chunk_types(&png), ["IHDR", "gAMA", "IDAT", "fiLe", "IEND"]);
+        assert_eq!(png.ancillary_chunk("IDAT").unwrap(), idat);
+        assert_eq!(png.get_file("a").unwrap(), b"a");
+        assert!(png.validate_structure().is_empty());
+    }
+}
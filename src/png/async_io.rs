@@ -0,0 +1,43 @@
+use std::future::Future;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::Png;
+use crate::PngFilesError;
+
+impl Png {
+    /// Read a PNG from `reader` until the end and parse it, like `new`
+    ///
+    /// Only reading is asynchronous; the bytes are buffered and parsed once they're all in,
+    /// which is cheap since parsing doesn't decode any file
+    pub async fn from_async_read<R: AsyncRead + Unpin>(
+        mut reader: R,
+    ) -> Result<Self, PngFilesError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+
+        Self::new(data)
+    }
+
+    /// Serialize the PNG like `write_to`, writing it to `writer` asynchronously
+    /// Returns the number of bytes written
+    ///
+    /// Serializing happens right away, before the returned future is first polled, so the
+    /// future doesn't borrow the `Png` and can be sent to another task
+    pub fn write_to_async<W: AsyncWrite + Unpin + Send>(
+        &self,
+        mut writer: W,
+    ) -> impl Future<Output = Result<usize, PngFilesError>> + Send {
+        let mut bytes = Vec::with_capacity(self.serialized_len());
+        let result = self.write_to(&mut bytes);
+
+        async move {
+            let written = result?;
+
+            writer.write_all(&bytes).await?;
+            writer.flush().await?;
+
+            Ok(written)
+        }
+    }
+}
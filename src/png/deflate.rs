@@ -0,0 +1,282 @@
+use std::{
+    borrow::Cow,
+    io::{self, Cursor, Read, Write},
+};
+
+use flate2::{
+    read::{DeflateDecoder, GzDecoder},
+    write::{DeflateEncoder, GzEncoder},
+    Compress, Compression, FlushCompress, Status,
+};
+use miniz_oxide::deflate::core::{
+    compress, create_comp_flags_from_zip_params, CompressionStrategy, CompressorOxide, TDEFLFlush,
+    TDEFLStatus,
+};
+
+use super::DeflateStrategy;
+use crate::PngFilesError;
+
+// deflate can only refer back this far, so only the tail of a dictionary is ever used
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Deflate `data`, optionally with a preset dictionary
+pub(super) fn deflate(
+    data: &[u8],
+    level: Compression,
+    dictionary: Option<&[u8]>,
+) -> Result<Vec<u8>, PngFilesError> {
+    let Some(dictionary) = dictionary else {
+        let mut deflater = DeflateEncoder::new(Vec::new(), level);
+        deflater.write_all(data)?;
+        return Ok(deflater.finish()?);
+    };
+
+    // the rust backend has no preset dictionary support, so instead the dictionary is
+    // compressed first and flushed to a block boundary, then its output is thrown away.
+    // what remains is a stream whose back references can point into the dictionary
+    let mut compress = Compress::new(level, false);
+
+    let mut primer = Vec::new();
+    compress_all(
+        &mut compress,
+        window(dictionary),
+        &mut primer,
+        FlushCompress::Sync,
+    )?;
+
+    let mut out = Vec::new();
+    compress_all(&mut compress, data, &mut out, FlushCompress::Finish)?;
+
+    Ok(out)
+}
+
+/// Deflate `data` with a specific strategy
+///
+/// flate2 doesn't expose strategies, so this drives its miniz_oxide backend directly
+pub(super) fn deflate_with_strategy(
+    data: &[u8],
+    level: Compression,
+    strategy: DeflateStrategy,
+) -> Result<Vec<u8>, PngFilesError> {
+    let strategy = match strategy {
+        DeflateStrategy::Default => CompressionStrategy::Default,
+        DeflateStrategy::Filtered => CompressionStrategy::Filtered,
+        DeflateStrategy::HuffmanOnly => CompressionStrategy::HuffmanOnly,
+        DeflateStrategy::Rle => CompressionStrategy::RLE,
+        DeflateStrategy::Fixed => CompressionStrategy::Fixed,
+    };
+
+    // negative window bits means a raw deflate stream, same as DeflateEncoder writes
+    let flags = create_comp_flags_from_zip_params(level.level() as i32, -15, strategy as i32);
+    let mut compressor = CompressorOxide::new(flags);
+
+    let mut out = vec![0; data.len() / 2 + 1024];
+    let mut read = 0;
+    let mut written = 0;
+
+    loop {
+        let (status, in_len, out_len) = compress(
+            &mut compressor,
+            &data[read..],
+            &mut out[written..],
+            TDEFLFlush::Finish,
+        );
+
+        read += in_len;
+        written += out_len;
+
+        match status {
+            TDEFLStatus::Done => {
+                out.truncate(written);
+                return Ok(out);
+            }
+
+            // ran out of output space
+            TDEFLStatus::Okay => out.resize(out.len() * 2, 0),
+
+            _ => Err(PngFilesError::Msg(Cow::Borrowed("Failed to deflate data")))?,
+        }
+    }
+}
+
+/// Inflate `data`, which must have been deflated with the same dictionary (if any)
+///
+/// Inflation stops as soon as the output goes over `max` bytes, so a tiny chunk can't
+/// blow up into gigabytes of memory
+pub(super) fn inflate(
+    data: &[u8],
+    max: usize,
+    dictionary: Option<&[u8]>,
+) -> Result<Vec<u8>, PngFilesError> {
+    // read one byte past the limit so we can tell "exactly max" apart from "over max"
+    let data = inflate_prefix(data, max.saturating_add(1), dictionary)?;
+    check_limit(data, max)
+}
+
+/// Inflate only the first `len` bytes of `data`, or all of it if it's shorter
+pub(super) fn inflate_prefix(
+    data: &[u8],
+    len: usize,
+    dictionary: Option<&[u8]>,
+) -> Result<Vec<u8>, PngFilesError> {
+    let dictionary = dictionary.map(window).unwrap_or_default();
+
+    // deflate blocks only share the sliding window, so feeding the dictionary in as a
+    // stored block first puts the decoder in the same state the encoder was in
+    let prefix = stored_block(dictionary);
+    let mut decoder = DeflateDecoder::new(Cursor::new(prefix).chain(data));
+
+    // skip the dictionary itself
+    io::copy(
+        &mut (&mut decoder).take(dictionary.len() as u64),
+        &mut io::sink(),
+    )?;
+
+    let mut data = Vec::new();
+    decoder.take(len as u64).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+/// Compress `data` into a complete gzip stream, header and trailer included
+pub(super) fn gzip(data: &[u8], level: Compression) -> Result<Vec<u8>, PngFilesError> {
+    let mut encoder = GzEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+
+    Ok(encoder.finish()?)
+}
+
+/// Decompress a gzip stream, checking its crc, with the same limit as `inflate`
+pub(super) fn gunzip(data: &[u8], max: usize) -> Result<Vec<u8>, PngFilesError> {
+    let data = gunzip_prefix(data, max.saturating_add(1))?;
+    check_limit(data, max)
+}
+
+/// Decompress only the first `len` bytes of a gzip stream
+/// The crc is only checked if the whole stream was read
+pub(super) fn gunzip_prefix(data: &[u8], len: usize) -> Result<Vec<u8>, PngFilesError> {
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .take(len as u64)
+        .read_to_end(&mut out)?;
+
+    Ok(out)
+}
+
+fn check_limit(data: Vec<u8>, max: usize) -> Result<Vec<u8>, PngFilesError> {
+    if data.len() > max {
+        Err(PngFilesError::DecompressLimit(max))?;
+    }
+
+    Ok(data)
+}
+
+fn window(dictionary: &[u8]) -> &[u8] {
+    &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..]
+}
+
+// a single non-final stored (uncompressed) deflate block
+// data must fit in one block, which the window size guarantees
+fn stored_block(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    let len = data.len() as u16;
+
+    let mut block = Vec::with_capacity(1 + 2 + 2 + data.len());
+    // BFINAL = 0, BTYPE = 00 (stored), padded to the byte boundary
+    block.push(0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(&(!len).to_le_bytes());
+    block.extend_from_slice(data);
+
+    block
+}
+
+// run all of input through the compressor, growing out as needed
+fn compress_all(
+    compress: &mut Compress,
+    input: &[u8],
+    out: &mut Vec<u8>,
+    flush: FlushCompress,
+) -> Result<(), PngFilesError> {
+    let start = compress.total_in();
+
+    loop {
+        out.reserve(input.len() / 2 + 1024);
+
+        let consumed = (compress.total_in() - start) as usize;
+        let status = compress.compress_vec(&input[consumed..], out, flush)?;
+        let consumed = (compress.total_in() - start) as usize;
+
+        match status {
+            Status::StreamEnd => return Ok(()),
+
+            // output space left over means the flush went through completely
+            _ if flush != FlushCompress::Finish
+                && consumed == input.len()
+                && out.len() < out.capacity() =>
+            {
+                return Ok(())
+            }
+
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRATEGIES: [DeflateStrategy; 5] = [
+        DeflateStrategy::Default,
+        DeflateStrategy::Filtered,
+        DeflateStrategy::HuffmanOnly,
+        DeflateStrategy::Rle,
+        DeflateStrategy::Fixed,
+    ];
+
+    // long runs of a few values, like a simple bitmap
+    fn runs() -> Vec<u8> {
+        (0..64u8)
+            .flat_map(|i| vec![i % 3; 100 + i as usize])
+            .collect()
+    }
+
+    #[test]
+    fn every_strategy_inflates() {
+        let data = runs();
+
+        for strategy in STRATEGIES {
+            for level in [1, 6, 9] {
+                let deflated =
+                    deflate_with_strategy(&data, Compression::new(level), strategy).unwrap();
+
+                assert_eq!(
+                    inflate(&deflated, data.len(), None).unwrap(),
+                    data,
+                    "{strategy:?} at level {level}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rle_compresses_runs() {
+        let data = runs();
+        let deflated =
+            deflate_with_strategy(&data, Compression::best(), DeflateStrategy::Rle).unwrap();
+
+        assert!(deflated.len() < data.len() / 10);
+    }
+
+    #[test]
+    fn empty_data_inflates() {
+        for strategy in STRATEGIES {
+            let deflated = deflate_with_strategy(&[], Compression::best(), strategy).unwrap();
+            assert_eq!(inflate(&deflated, 0, None).unwrap(), b"");
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::borrow::Cow;
+
+use super::{FileMeta, Png};
+use crate::PngFilesError;
+
+impl Png {
+    /// insert a file stored as a binary diff (bsdiff) from `base` to `new`, so only what
+    /// changed takes up space, e.g. for shipping an update to a file the reader already has
+    /// `replace` overwrites existing key if it exists
+    ///
+    /// Read it back with `get_file_with_base` and the same base. Everything else that reads
+    /// files, like `get_file`, returns the diff itself
+    pub fn insert_file_delta(
+        &mut self,
+        key: &str,
+        base: &[u8],
+        new: &[u8],
+        replace: bool,
+    ) -> Result<(), PngFilesError> {
+        // fail before diffing, which is the expensive part
+        self.existing_file(key, replace)?;
+
+        let mut patch = Vec::new();
+        bsdiff::diff(base, new, &mut patch)?;
+
+        // the diff is mostly zeroes where the files agree, so deflating it as usual is
+        // what makes it small
+        let meta = FileMeta {
+            delta: Some(crc32fast::hash(base)),
+            ..Default::default()
+        };
+
+        self.put_file(key, patch, None, meta, self.deflate_options, replace)
+    }
+
+    /// Get a file, applying its diff to `base` if it was inserted with `insert_file_delta`
+    /// Files stored in full are returned as-is, ignoring `base`
+    ///
+    /// Fails if `base` isn't the one the diff was made against, or the file fails to
+    /// decode. Returns none if the key was not found
+    pub fn get_file_with_base(
+        &self,
+        key: &str,
+        base: &[u8],
+    ) -> Result<Option<Vec<u8>>, PngFilesError> {
+        let Some(idx) = self.find_file_index(key) else {
+            return Ok(None);
+        };
+
+        let chunk = &self.chunks[idx];
+        let data = self.decode_chunk(chunk)?;
+
+        let Some(crc) = self.chunk_file(chunk)?.meta.delta else {
+            return Ok(Some(data));
+        };
+
+        if crc32fast::hash(base) != crc {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "{key} is a diff against a different base"
+            ))))?;
+        }
+
+        let mut new = Vec::new();
+        bsdiff::patch(base, &mut data.as_slice(), &mut new)?;
+
+        // the reconstructed file is held to the same limit as inflated files
+        if new.len() > self.max_decompressed {
+            Err(PngFilesError::DecompressLimit(self.max_decompressed))?;
+        }
+
+        Ok(Some(new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions() -> (Vec<u8>, Vec<u8>) {
+        let base = (0..20_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut new = base.clone();
+        new[1000..1010].copy_from_slice(b"0123456789");
+        new.extend_from_slice(b"appended");
+
+        (base, new)
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let (base, new) = versions();
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file_delta("a", &base, &new, false).unwrap();
+        png.insert_file("full", new.clone(), false).unwrap();
+
+        let png = Png::new(png.into_bytes()).unwrap();
+        assert_eq!(png.get_file_with_base("a", &base).unwrap().unwrap(), new);
+        // only what changed is stored
+        assert!(
+            png.file_info("a").unwrap().compressed_len
+                < png.file_info("full").unwrap().compressed_len
+        );
+
+        // files stored in full ignore the base
+        assert_eq!(png.get_file_with_base("full", b"").unwrap().unwrap(), new);
+        assert_eq!(png.get_file_with_base("missing", &base).unwrap(), None);
+    }
+
+    #[test]
+    fn delta_needs_its_base() {
+        let (base, new) = versions();
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file_delta("a", &base, &new, false).unwrap();
+
+        assert!(png.get_file_with_base("a", &new).is_err());
+        assert!(png.insert_file_delta("a", &base, &new, false).is_err());
+        png.insert_file_delta("a", &new, &base, true).unwrap();
+        assert_eq!(png.get_file_with_base("a", &new).unwrap().unwrap(), base);
+    }
+
+    #[test]
+    fn delta_is_held_to_the_limit() {
+        let (base, new) = versions();
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file_delta("a", &base, &new, false).unwrap();
+        png.set_max_decompressed(base.len());
+
+        assert!(matches!(
+            png.get_file_with_base("a", &base),
+            Err(PngFilesError::DecompressLimit(_))
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    fs,
+    io::BufWriter,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+};
+
+use super::Png;
+use crate::PngFilesError;
+
+/// A PNG opened for editing in place, see `Png::edit_file`
+///
+/// Derefs to the `Png`, so files can be inserted and removed as usual. The result is
+/// written back to the path when the guard is dropped, or with `commit` to see whether
+/// it worked, but not when it's dropped by a panic unwinding. Writing goes to a temporary
+/// file next to the image which is then renamed over it, so a crash midway leaves either
+/// the old or the new image, never a mix
+pub struct EditGuard {
+    // only none once commit or discard took it, which consume the guard
+    png: Option<Png>,
+    path: PathBuf,
+}
+
+impl Png {
+    /// Open the PNG at `path` for editing, writing it back when the returned guard is
+    /// dropped, or with `EditGuard::commit`
+    ///
+    /// Use `EditGuard::discard` to throw the changes away instead
+    pub fn edit_file<P: AsRef<Path>>(path: P) -> Result<EditGuard, PngFilesError> {
+        let path = path.as_ref();
+
+        let data = fs::read(path).map_err(|e| PngFilesError::from(e).with_path("read", path))?;
+
+        Ok(EditGuard {
+            png: Some(Png::new(data)?),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl EditGuard {
+    /// The path the PNG is written back to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the PNG back to its path now
+    /// Returns the number of bytes written
+    ///
+    /// Dropping the guard does the same but has nowhere to report a failure to
+    pub fn commit(mut self) -> Result<usize, PngFilesError> {
+        let png = self.png.take().unwrap();
+        write_atomic(&png, &self.path)
+    }
+
+    /// Abandon the changes, leaving the file on disk as it was
+    pub fn discard(mut self) {
+        self.png = None;
+    }
+}
+
+impl Deref for EditGuard {
+    type Target = Png;
+
+    fn deref(&self) -> &Png {
+        self.png.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for EditGuard {
+    fn deref_mut(&mut self) -> &mut Png {
+        self.png.as_mut().unwrap()
+    }
+}
+
+impl Drop for EditGuard {
+    fn drop(&mut self) {
+        // a panic midway through editing would otherwise save the half done changes
+        if std::thread::panicking() {
+            return;
+        }
+
+        if let Some(png) = self.png.take() {
+            // errors can't be returned from drop, commit is there for callers who care
+            let _ = write_atomic(&png, &self.path);
+        }
+    }
+}
+
+// write to a temporary file in the same directory, then rename it over path
+// the rename only happens once the data is on disk, so path is never left half written
+fn write_atomic(png: &Png, path: &Path) -> Result<usize, PngFilesError> {
+    let name = path
+        .file_name()
+        .ok_or(PngFilesError::Msg(Cow::Owned(format!(
+            "{} is not a file",
+            path.display()
+        ))))?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        let file =
+            fs::File::create(&tmp).map_err(|e| PngFilesError::from(e).with_path("create", &tmp))?;
+
+        let written = png
+            .write_to(BufWriter::new(&file))
+            .map_err(|e| e.with_path("write", &tmp))?;
+        file.sync_all()
+            .map_err(|e| PngFilesError::from(e).with_path("sync", &tmp))?;
+
+        // keep the original's permissions rather than the defaults of a new file
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&tmp, metadata.permissions())
+                .map_err(|e| PngFilesError::from(e).with_path("set permissions of", &tmp))?;
+        }
+
+        fs::rename(&tmp, path).map_err(|e| PngFilesError::from(e).with_path("rename", &tmp))?;
+
+        Ok(written)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+
+    // a blank image with one file, at a fresh path under the system temp dir
+    fn image(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("png-files-{name}-{}.png", std::process::id()));
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file("old", b"old".to_vec(), false).unwrap();
+        png.save(&path).unwrap();
+
+        path
+    }
+
+    fn keys(path: &Path) -> Vec<String> {
+        let png = Png::new(fs::read(path).unwrap()).unwrap();
+        png.files_in_order()
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn commit_writes_changes() {
+        let path = image("edit-commit");
+
+        let mut guard = Png::edit_file(&path).unwrap();
+        guard.insert_file("new", b"new".to_vec(), false).unwrap();
+        guard.commit().unwrap();
+
+        assert_eq!(keys(&path), ["old", "new"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_writes_changes() {
+        let path = image("edit-drop");
+
+        {
+            let mut guard = Png::edit_file(&path).unwrap();
+            guard.remove_file("old");
+        }
+
+        assert!(keys(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn discard_keeps_file() {
+        let path = image("edit-discard");
+        let before = fs::read(&path).unwrap();
+
+        let mut guard = Png::edit_file(&path).unwrap();
+        guard.insert_file("new", b"new".to_vec(), false).unwrap();
+        guard.discard();
+
+        assert_eq!(fs::read(&path).unwrap(), before);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn panic_keeps_file() {
+        let path = image("edit-panic");
+        let before = fs::read(&path).unwrap();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = Png::edit_file(&path).unwrap();
+            guard.insert_file("half", b"half".to_vec(), false).unwrap();
+            panic!("edit failed midway");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), before);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+// shell style glob matching for keys, see Png::keys_matching_glob
+//
+// `/` separates path segments: `*`, `?` and `[...]` never match it, only `**` does.
+// a `[` without a closing `]` is matched literally
+
+/// Whether `key` matches the glob `pattern`
+pub(super) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let key = key.chars().collect::<Vec<_>>();
+
+    Matcher {
+        pattern: &pattern,
+        key: &key,
+        failed: vec![false; (pattern.len() + 1) * (key.len() + 1)],
+    }
+    .matches_from(0, 0)
+}
+
+// every (pattern position, key position) pair is tried at most once, since stars would
+// otherwise try the same rest of the pattern against the same rest of the key over and
+// over, which takes exponential time with several of them
+struct Matcher<'a> {
+    pattern: &'a [char],
+    key: &'a [char],
+    // pairs already known not to match, by p * (key.len() + 1) + k
+    failed: Vec<bool>,
+}
+
+impl Matcher<'_> {
+    fn matches_from(&mut self, p: usize, k: usize) -> bool {
+        let slot = p * (self.key.len() + 1) + k;
+        if self.failed[slot] {
+            return false;
+        }
+
+        let matched = self.try_match(p, k);
+        if !matched {
+            self.failed[slot] = true;
+        }
+
+        matched
+    }
+
+    fn try_match(&mut self, p: usize, k: usize) -> bool {
+        let key = &self.key[k..];
+
+        match self.pattern[p..] {
+            [] => key.is_empty(),
+
+            ['*', '*', ..] => {
+                // `a/**/b` also matches `a/b`, so the `**/` may stand for no segment at all
+                if self.pattern.get(p + 2) == Some(&'/') && self.matches_from(p + 3, k) {
+                    return true;
+                }
+
+                (k..=self.key.len()).any(|i| self.matches_from(p + 2, i))
+            }
+
+            ['*', ..] => {
+                // as far as the end of the segment
+                let end = key.iter().position(|&c| c == '/').unwrap_or(key.len());
+                (k..=k + end).any(|i| self.matches_from(p + 1, i))
+            }
+
+            ['?', ..] => match key {
+                [c, ..] if *c != '/' => self.matches_from(p + 1, k + 1),
+                _ => false,
+            },
+
+            ['[', ..] => match (parse_class(&self.pattern[p + 1..]), key) {
+                (Some((matched, len)), [c, ..]) => {
+                    *c != '/' && matched(*c) && self.matches_from(p + 1 + len, k + 1)
+                }
+                (Some(_), []) => false,
+                // not a class after all
+                (None, _) => self.literal(p, k),
+            },
+
+            [_, ..] => self.literal(p, k),
+        }
+    }
+
+    fn literal(&mut self, p: usize, k: usize) -> bool {
+        match self.key.get(k) {
+            Some(&c) => c == self.pattern[p] && self.matches_from(p + 1, k + 1),
+            None => false,
+        }
+    }
+}
+
+// parse the inside of a `[...]` class, returning a predicate for it and how much of the
+// pattern it took up including the closing `]`, or none if it's never closed
+fn parse_class(class: &[char]) -> Option<(impl Fn(char) -> bool, usize)> {
+    let (negated, skip) = match class {
+        ['!' | '^', ..] => (true, 1),
+        _ => (false, 0),
+    };
+    let class = &class[skip..];
+
+    // a `]` right at the start is part of the class rather than closing it
+    let end = class.iter().skip(1).position(|&c| c == ']')? + 1;
+    let items = &class[..end];
+
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < items.len() {
+        match items[i..] {
+            [start, '-', end, ..] => {
+                ranges.push((start, end));
+                i += 3;
+            }
+            _ => {
+                ranges.push((items[i], items[i]));
+                i += 1;
+            }
+        }
+    }
+
+    let matched = move |c: char| ranges.iter().any(|&(s, e)| s <= c && c <= e) != negated;
+
+    Some((matched, skip + end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::matches;
+
+    #[test]
+    fn literals_and_wildcards() {
+        assert!(matches("a.txt", "a.txt"));
+        assert!(!matches("a.txt", "b.txt"));
+        assert!(matches("*.txt", "a.txt"));
+        assert!(!matches("*.txt", "dir/a.txt"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(matches("dir/*", "dir/a"));
+        assert!(!matches("dir/*", "dir/a/b"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("assets/**/*.png", "assets/a/b/c.png"));
+        assert!(matches("assets/**/*.png", "assets/c.png"));
+        assert!(!matches("assets/**/*.png", "other/c.png"));
+        assert!(matches("**.txt", "a/b.txt"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(!matches("[abc].txt", "d.txt"));
+        assert!(matches("[a-c].txt", "b.txt"));
+        assert!(matches("[!a-c].txt", "d.txt"));
+        assert!(!matches("[^a-c].txt", "a.txt"));
+        assert!(matches("[]].txt", "].txt"));
+        assert!(!matches("a[/]b", "a/b"));
+        // never closed, so literal
+        assert!(matches("[a.txt", "[a.txt"));
+    }
+
+    #[test]
+    fn many_stars_stay_fast() {
+        let key = "a".repeat(200);
+
+        let start = Instant::now();
+        assert!(!matches("**a**a**a**a**a**a**b", &key));
+        assert!(!matches("*a*a*a*a*a*a*a*a*b", &key));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
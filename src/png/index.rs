@@ -0,0 +1,340 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use bincode::{Decode, Encode};
+
+use super::{ChunkType, Decoders, Png, DEFAULT_MAX_DECOMPRESSED, DICT_CHUNK_TYPE};
+use crate::PngFilesError;
+
+#[derive(Debug, Default, Encode, Decode)]
+struct Index {
+    files: Vec<IndexEntry>,
+    dictionaries: Vec<DictionaryEntry>,
+}
+
+// where a file's chunk data lives inside the serialized png
+// a split file has an entry for every part, in order
+#[derive(Debug, Encode, Decode)]
+struct IndexEntry {
+    key: String,
+    // offset of the chunk data (not the chunk start) from the start of the file
+    offset: u64,
+    len: u32,
+    // the chunk is deflated as a whole, see Png::set_deflate_keys
+    packed: bool,
+}
+
+// where a preset dictionary's chunk data lives inside the serialized png
+#[derive(Debug, Encode, Decode)]
+struct DictionaryEntry {
+    crc: u32,
+    offset: u64,
+    len: u32,
+}
+
+/// A PNG opened alongside a sidecar index, able to read any file with a single seek
+///
+/// Created with `Png::open_with_index`. The index must belong to the exact PNG it was
+/// generated with; a stale index is detected on read when the key doesn't match.
+pub struct IndexedPng {
+    file: fs::File,
+    // (offset, len, packed) of the file's chunk, followed by the rest of its parts if
+    // it's split
+    index: HashMap<String, Vec<(u64, u32, bool)>>,
+    dictionaries: HashMap<u32, (u64, u32)>,
+    max_decompressed: usize,
+    decoders: Decoders,
+}
+
+impl Png {
+    /// Serialize the PNG, also returning a sidecar index mapping each file's key to
+    /// where its chunk data is in the serialized PNG
+    ///
+    /// The PNG itself is unchanged and stays a standard file, the index is optional
+    pub fn into_bytes_with_index(self) -> Result<(Vec<u8>, Vec<u8>), PngFilesError> {
+        let mut bytes = Vec::with_capacity(self.serialized_len());
+        bytes.extend_from_slice(&self.signature);
+
+        let mut index = Index::default();
+
+        let comment = self.auto_comment();
+        for chunk in self.output_chunks(comment.as_ref()) {
+            // skip len + chunk type
+            let offset = (bytes.len() + 4 + 4) as u64;
+
+            if let Some(key) = chunk.chunk_type.file_key() {
+                index.files.push(IndexEntry {
+                    key: key.to_owned(),
+                    offset,
+                    len: chunk.len,
+                    packed: matches!(chunk.chunk_type, ChunkType::PackedFile { .. }),
+                });
+            } else if chunk.chunk_type.as_ref() == DICT_CHUNK_TYPE {
+                index.dictionaries.push(DictionaryEntry {
+                    crc: crc32fast::hash(chunk),
+                    offset,
+                    len: chunk.len,
+                });
+            }
+
+            bytes.extend(chunk.to_bytes());
+        }
+
+        let index = bincode::encode_to_vec(index, bincode::config::standard())?;
+
+        Ok((bytes, index))
+    }
+
+    /// Open a PNG using a sidecar index made by `into_bytes_with_index`
+    /// Files are read straight from their offsets, without scanning the chunks
+    pub fn open_with_index<P: AsRef<Path>, I: AsRef<Path>>(
+        png_path: P,
+        index_path: I,
+    ) -> Result<IndexedPng, PngFilesError> {
+        let png_path = png_path.as_ref();
+        let index_path = index_path.as_ref();
+
+        let index = fs::read(index_path)
+            .map_err(|e| PngFilesError::from(e).with_path("read", index_path))?;
+        let (index, _) =
+            bincode::decode_from_slice::<Index, _>(&index, bincode::config::standard())?;
+
+        let file = fs::File::open(png_path)
+            .map_err(|e| PngFilesError::from(e).with_path("open", png_path))?;
+
+        let mut files = HashMap::<_, Vec<_>>::new();
+        for e in index.files {
+            files
+                .entry(e.key)
+                .or_default()
+                .push((e.offset, e.len, e.packed));
+        }
+
+        Ok(IndexedPng {
+            file,
+            index: files,
+            dictionaries: index
+                .dictionaries
+                .into_iter()
+                .map(|e| (e.crc, (e.offset, e.len)))
+                .collect(),
+            max_decompressed: DEFAULT_MAX_DECOMPRESSED,
+            decoders: HashMap::new(),
+        })
+    }
+}
+
+impl IndexedPng {
+    /// Set the maximum size a single file is allowed to inflate to when retrieved
+    /// Defaults to `DEFAULT_MAX_DECOMPRESSED`
+    pub fn set_max_decompressed(&mut self, max: usize) {
+        self.max_decompressed = max;
+    }
+
+    /// Register the decoder for files stored with `CompressionMethod::Custom(id)`, see
+    /// `Png::register_decoder`
+    pub fn register_decoder<F>(&mut self, id: u32, decoder: F)
+    where
+        F: Fn(&[u8], usize) -> Result<Vec<u8>, PngFilesError> + 'static,
+    {
+        self.decoders.insert(id, Box::new(decoder));
+    }
+
+    /// Keys of all indexed files
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    /// Read a file through the index
+    /// Returns none if the key isn't in the index
+    pub fn get_file(&self, key: &str) -> Result<Option<Vec<u8>>, PngFilesError> {
+        let Some(entries) = self.index.get(key) else {
+            return Ok(None);
+        };
+
+        let mut parts = Vec::with_capacity(entries.len());
+        for &(offset, len, _) in entries {
+            parts.push(self.read_at(offset, len)?);
+        }
+
+        let mut file = match entries[0] {
+            (_, _, true) => Png::decode_packed(&parts[0], self.max_decompressed)?,
+            _ => Png::decode_file(&parts[0])?,
+        };
+
+        if file.key != key {
+            Err(PngFilesError::Msg(Cow::Borrowed(
+                "Index does not match PNG file",
+            )))?;
+        }
+
+        if let Some((_, count)) = file.meta.part {
+            if count as usize != parts.len() {
+                Err(PngFilesError::Msg(Cow::Borrowed(
+                    "Index does not match PNG file",
+                )))?;
+            }
+
+            let mut data = file.data.into_owned();
+            for (idx, part) in parts.iter().enumerate().skip(1) {
+                let part = Png::decode_file(part)?;
+
+                if part.key != key || part.meta.part.map(|(index, _)| index) != Some(idx as u32) {
+                    Err(PngFilesError::Msg(Cow::Borrowed(
+                        "Index does not match PNG file",
+                    )))?;
+                }
+
+                data.extend_from_slice(&part.data);
+            }
+
+            file.data = Cow::Owned(data);
+        }
+
+        let dictionary = match file.meta.dictionary {
+            Some(crc) => {
+                let &(offset, len) =
+                    self.dictionaries
+                        .get(&crc)
+                        .ok_or(PngFilesError::Msg(Cow::Borrowed(
+                            "Dictionary for file not found",
+                        )))?;
+
+                Some(self.read_at(offset, len)?)
+            }
+
+            None => None,
+        };
+
+        file.decode_data(self.max_decompressed, dictionary.as_deref(), &self.decoders)
+            .map(|data| Some(data.into_owned()))
+    }
+
+    fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, PngFilesError> {
+        // a stale or corrupt index could claim anything, don't allocate for more than
+        // the png holds
+        let file_len = self.file.metadata()?.len();
+        if offset
+            .checked_add(len.into())
+            .is_none_or(|end| end > file_len)
+        {
+            Err(PngFilesError::Msg(Cow::Owned(format!(
+                "Index points past the end of the PNG ({len} bytes at {offset}, the PNG is \
+                 {file_len} bytes)"
+            ))))?;
+        }
+
+        let mut data = vec![0; len as usize];
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompressionMethod;
+
+    // reverses the bytes, standing in for a real codec
+    fn reverse(data: &[u8], _max: usize) -> Result<Vec<u8>, PngFilesError> {
+        Ok(data.iter().rev().copied().collect())
+    }
+
+    #[test]
+    fn custom_method_reads_through_index() {
+        let dir = std::env::temp_dir();
+        let png_path = dir.join(format!("png-files-index-{}.png", std::process::id()));
+        let index_path = png_path.with_extension("idx");
+
+        let mut png = Png::new_blank(1, 1).unwrap();
+        png.insert_file_with_method(
+            "custom",
+            b"olleh".to_vec(),
+            CompressionMethod::Custom(7),
+            false,
+        )
+        .unwrap();
+        png.insert_file("plain", b"plain".to_vec(), false).unwrap();
+
+        let (bytes, index) = png.into_bytes_with_index().unwrap();
+        fs::write(&png_path, bytes).unwrap();
+        fs::write(&index_path, index).unwrap();
+
+        let mut indexed = Png::open_with_index(&png_path, &index_path).unwrap();
+        assert_eq!(indexed.get_file("plain").unwrap().unwrap(), b"plain");
+        assert!(indexed.get_file("custom").is_err());
+
+        indexed.register_decoder(7, reverse);
+        assert_eq!(indexed.get_file("custom").unwrap().unwrap(), b"hello");
+
+        fs::remove_file(&png_path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn deflated_keys_read_through_index() {
+        let dir = std::env::temp_dir();
+        let png_path = dir.join(format!("png-files-index-packed-{}.png", std::process::id()));
+        let index_path = png_path.with_extension("idx");
+
+        let key = format!("{}file", "dir/".repeat(50));
+
+        let mut png = Png::new_blank(1, 1).unwrap().with_deflate_keys(true);
+        png.insert_file(&key, b"packed".to_vec(), false).unwrap();
+
+        let (bytes, index) = png.into_bytes_with_index().unwrap();
+        fs::write(&png_path, bytes).unwrap();
+        fs::write(&index_path, index).unwrap();
+
+        let indexed = Png::open_with_index(&png_path, &index_path).unwrap();
+        assert_eq!(indexed.get_file(&key).unwrap().unwrap(), b"packed");
+
+        fs::remove_file(&png_path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn index_past_the_end_is_rejected() {
+        let dir = std::env::temp_dir();
+        let png_path = dir.join(format!(
+            "png-files-index-corrupt-{}.png",
+            std::process::id()
+        ));
+        let index_path = png_path.with_extension("idx");
+
+        fs::write(&png_path, Png::new_blank(1, 1).unwrap().into_bytes()).unwrap();
+
+        let index = Index {
+            files: vec![IndexEntry {
+                key: "a".to_owned(),
+                offset: 8,
+                len: u32::MAX,
+                packed: false,
+            }],
+            dictionaries: Vec::new(),
+        };
+        fs::write(
+            &index_path,
+            bincode::encode_to_vec(index, bincode::config::standard()).unwrap(),
+        )
+        .unwrap();
+
+        let indexed = Png::open_with_index(&png_path, &index_path).unwrap();
+        assert!(matches!(
+            indexed.get_file("a"),
+            Err(PngFilesError::Msg(msg)) if msg.starts_with("Index points past the end")
+        ));
+
+        fs::remove_file(&png_path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+    }
+}
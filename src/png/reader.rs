@@ -0,0 +1,92 @@
+use std::io::{self, Read};
+
+use super::PngChunk;
+
+// pull based serializer behind `Png::into_reader`
+//
+// walks the signature, then every chunk piece by piece (len, type, data, crc), copying
+// straight out of the chunk without serializing it into a buffer first
+pub(super) struct PngReader<I> {
+    signature: [u8; 8],
+    chunks: I,
+    // chunk currently being read, none while still in the signature
+    current: Option<PngChunk>,
+    // read position within the signature or the current chunk
+    pos: usize,
+}
+
+impl<I: Iterator<Item = PngChunk>> PngReader<I> {
+    pub(super) fn new(signature: [u8; 8], chunks: I) -> Self {
+        Self {
+            signature,
+            chunks,
+            current: None,
+            pos: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = PngChunk>> Read for PngReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            let copied = match &self.current {
+                None => copy_from(&[&self.signature], self.pos, &mut buf[written..]),
+
+                Some(chunk) => copy_from(
+                    &[
+                        &chunk.len.to_be_bytes(),
+                        chunk.chunk_type.as_bytes(),
+                        chunk,
+                        &chunk.crc.to_be_bytes(),
+                    ],
+                    self.pos,
+                    &mut buf[written..],
+                ),
+            };
+
+            if copied > 0 {
+                self.pos += copied;
+                written += copied;
+                continue;
+            }
+
+            // current piece is done, move on to the next chunk
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.pos = 0;
+                }
+
+                None => break,
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+// copy as much as fits into `buf`, starting `pos` bytes into the concatenated `parts`
+fn copy_from(parts: &[&[u8]], mut pos: usize, buf: &mut [u8]) -> usize {
+    let mut copied = 0;
+
+    for part in parts {
+        if pos >= part.len() {
+            pos -= part.len();
+            continue;
+        }
+
+        let len = (part.len() - pos).min(buf.len() - copied);
+        buf[copied..copied + len].copy_from_slice(&part[pos..pos + len]);
+
+        copied += len;
+        pos = 0;
+
+        if copied == buf.len() {
+            break;
+        }
+    }
+
+    copied
+}
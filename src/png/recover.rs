@@ -0,0 +1,170 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use super::{
+    File, Png, CHUNK_TYPE, DEFAULT_MAX_DECOMPRESSED, DICT_CHUNK_TYPE, PACKED_CHUNK_TYPE,
+    PART_CHUNK_TYPE,
+};
+
+impl Png {
+    /// Salvage embedded files from a damaged PNG whose chunk chain is broken
+    ///
+    /// Instead of walking the chunks, `data` is scanned for the `fiLe`, `fiLz` (files
+    /// deflated as a whole) and `fiLp` (later parts of split files) chunk types and every
+    /// hit is read as a chunk on its own, using the length in front of it. Crcs aren't
+    /// checked since they may be what's damaged; a file has to fully decode instead
+    /// Parts of split files are joined back together, a file missing any part is dropped
+    /// Returns every recovered file in the order found. Best effort, never fails
+    pub fn recover_files(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        // files deflated with a dictionary refer to it by its crc
+        let dictionaries = chunks_of_type(data, DICT_CHUNK_TYPE)
+            .map(|dictionary| (crc32fast::hash(dictionary), dictionary))
+            .collect::<HashMap<_, _>>();
+
+        let mut chunks = [CHUNK_TYPE, PACKED_CHUNK_TYPE, PART_CHUNK_TYPE]
+            .into_iter()
+            .flat_map(|chunk_type| chunks_of_type(data, chunk_type).map(move |c| (chunk_type, c)))
+            .collect::<Vec<_>>();
+
+        // back in the order they're in, parts are only looked for after their first part
+        chunks.sort_by_key(|(_, chunk)| chunk.as_ptr());
+
+        let files = chunks
+            .into_iter()
+            .filter_map(|(chunk_type, chunk)| match chunk_type {
+                PACKED_CHUNK_TYPE => Self::decode_packed(chunk, DEFAULT_MAX_DECOMPRESSED).ok(),
+                _ => Self::decode_file(chunk).ok(),
+            })
+            .collect::<Vec<_>>();
+
+        files
+            .iter()
+            .enumerate()
+            // later parts of split files are joined onto their first part
+            .filter(|(_, file)| file.meta.part.is_none_or(|(index, _)| index == 0))
+            .filter_map(|(idx, file)| {
+                let dictionary = match file.meta.dictionary {
+                    Some(crc) => Some(*dictionaries.get(&crc)?),
+                    None => None,
+                };
+
+                let mut joined = File {
+                    key: Cow::Borrowed(&file.key),
+                    data: Cow::Borrowed(&file.data),
+                    meta: file.meta.clone(),
+                };
+
+                if let Some((_, count)) = file.meta.part {
+                    let mut data = file.data.to_vec();
+
+                    // parts come after the first, take the first match for every number
+                    for index in 1..count {
+                        let part = files[idx + 1..].iter().find(|part| {
+                            part.key == file.key && part.meta.part.is_some_and(|(i, _)| i == index)
+                        })?;
+
+                        data.extend_from_slice(&part.data);
+                    }
+
+                    joined.data = Cow::Owned(data);
+                }
+
+                let data = joined
+                    // custom methods can't be decoded, there's no png to register decoders on
+                    .decode_data(DEFAULT_MAX_DECOMPRESSED, dictionary, &HashMap::new())
+                    .ok()?;
+
+                Some((file.key.to_string(), data.into_owned()))
+            })
+            .collect()
+    }
+}
+
+// data of every plausible chunk of this type anywhere in `data`: the type preceded by a
+// length, with room for that much data and a crc after it
+fn chunks_of_type<'a>(data: &'a [u8], chunk_type: &'a str) -> impl Iterator<Item = &'a [u8]> {
+    data.windows(4)
+        .enumerate()
+        .filter(move |(_, window)| *window == chunk_type.as_bytes())
+        .filter_map(move |(pos, _)| {
+            let len = data.get(pos.checked_sub(4)?..pos)?;
+            let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+
+            let start = pos + 4;
+            let end = start.checked_add(len)?;
+            data.get(end..end.checked_add(4)?)?;
+
+            data.get(start..end)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_with(files: &[(&str, &[u8])], max_chunk_size: usize) -> Vec<u8> {
+        let mut png = Png::new_blank(1, 1)
+            .unwrap()
+            .with_max_chunk_size(max_chunk_size);
+        for &(key, data) in files {
+            png.insert_file(key, data.to_vec(), false).unwrap();
+        }
+
+        png.into_bytes()
+    }
+
+    #[test]
+    fn files_survive_a_broken_chain() {
+        let mut data = png_with(&[("a.txt", b"recover me")], usize::MAX);
+
+        // the IDAT length, so the chunks after it can't be walked to anymore
+        data[33..37].copy_from_slice(&[0xff; 4]);
+        // and the signature
+        data[0] = 0;
+        assert!(Png::new(data.clone()).is_err());
+
+        assert_eq!(
+            Png::recover_files(&data),
+            [("a.txt".to_owned(), b"recover me".to_vec())]
+        );
+    }
+
+    #[test]
+    fn split_files_are_joined() {
+        let big = (0..=255).cycle().take(2000).collect::<Vec<u8>>();
+        let data = png_with(&[("big", &big), ("small", b"small")], 64);
+
+        assert_eq!(
+            Png::recover_files(&data),
+            [
+                ("big".to_owned(), big),
+                ("small".to_owned(), b"small".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn files_missing_a_part_are_dropped() {
+        let big = (0..=255).cycle().take(2000).collect::<Vec<u8>>();
+        let mut data = png_with(&[("big", &big), ("small", b"small")], 64);
+
+        // corrupt the type of the last part of big
+        let last_part = data
+            .windows(4)
+            .rposition(|w| w == PART_CHUNK_TYPE.as_bytes())
+            .unwrap();
+        data[last_part] = b'x';
+
+        assert_eq!(
+            Png::recover_files(&data),
+            [("small".to_owned(), b"small".to_vec())]
+        );
+    }
+
+    #[test]
+    fn garbage_recovers_nothing() {
+        assert!(Png::recover_files(&[]).is_empty());
+        assert!(Png::recover_files(b"fiLe").is_empty());
+        assert!(Png::recover_files(b"\xff\xff\xff\xfffiLe\x00\x00").is_empty());
+        assert!(Png::recover_files(b"\x00\x00\x00\x02fiLe\x01\x02\x00\x00\x00\x00").is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::{borrow::Cow, fmt};
+
+use super::{Png, PNG_HEADER};
+use crate::PngFilesError;
+
+/// A violation of the PNG chunk ordering rules, see `Png::validate_structure`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StructureWarning {
+    /// The first chunk isn't IHDR
+    IhdrNotFirst,
+    /// The last chunk isn't IEND
+    IendNotLast,
+    /// There is no IDAT chunk
+    MissingIdat,
+    /// Other chunks appear between IDAT chunks
+    IdatNotContiguous,
+    /// A chunk that may appear at most once appears more than once
+    Duplicate(String),
+    /// A chunk must come before chunks of another type, but doesn't
+    NotBefore {
+        chunk_type: String,
+        before: &'static str,
+    },
+    /// A chunk must come after chunks of another type, but doesn't
+    NotAfter {
+        chunk_type: String,
+        after: &'static str,
+    },
+}
+
+impl fmt::Display for StructureWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IhdrNotFirst => write!(f, "IHDR is not the first chunk"),
+            Self::IendNotLast => write!(f, "IEND is not the last chunk"),
+            Self::MissingIdat => write!(f, "No IDAT chunk"),
+            Self::IdatNotContiguous => write!(f, "IDAT chunks not contiguous"),
+            Self::Duplicate(chunk_type) => write!(f, "{chunk_type} appears more than once"),
+            Self::NotBefore { chunk_type, before } => {
+                write!(f, "{chunk_type} must come before {before}")
+            }
+            Self::NotAfter { chunk_type, after } => {
+                write!(f, "{chunk_type} must come after {after}")
+            }
+        }
+    }
+}
+
+// chunks that may appear at most once
+const UNIQUE: &[&str] = &[
+    "IHDR", "PLTE", "IEND", "tRNS", "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCv", "cLLi",
+    "bKGD", "hIST", "pHYs", "eXIf", "tIME",
+];
+
+// chunks that must come before PLTE
+const BEFORE_PLTE: &[&str] = &[
+    "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCv", "cLLi",
+];
+
+// chunks that must come before the first IDAT
+const BEFORE_IDAT: &[&str] = &[
+    "PLTE", "tRNS", "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCv", "cLLi", "bKGD", "hIST",
+    "pHYs", "sPLT", "eXIf",
+];
+
+// chunks that must come after PLTE, if there is one
+const AFTER_PLTE: &[&str] = &["tRNS", "bKGD", "hIST"];
+
+impl Png {
+    /// Check the chunks against the PNG ordering rules, e.g. PLTE before IDAT and IDAT
+    /// chunks being contiguous
+    /// Only reports what's wrong, nothing is changed or rejected
+    pub fn validate_structure(&self) -> Vec<StructureWarning> {
+        let types = self
+            .chunks
+            .iter()
+            .map(|c| c.chunk_type.as_ref())
+            .collect::<Vec<_>>();
+
+        let first = |chunk_type: &str| types.iter().position(|&t| t == chunk_type);
+
+        let mut warnings = Vec::new();
+
+        if types.first() != Some(&"IHDR") {
+            warnings.push(StructureWarning::IhdrNotFirst);
+        }
+
+        if types.last() != Some(&"IEND") {
+            warnings.push(StructureWarning::IendNotLast);
+        }
+
+        match (first("IDAT"), types.iter().rposition(|&t| t == "IDAT")) {
+            (Some(first), Some(last)) => {
+                if types[first..=last].iter().any(|&t| t != "IDAT") {
+                    warnings.push(StructureWarning::IdatNotContiguous);
+                }
+            }
+
+            _ => warnings.push(StructureWarning::MissingIdat),
+        }
+
+        for &chunk_type in UNIQUE {
+            if types.iter().filter(|&&t| t == chunk_type).count() > 1 {
+                warnings.push(StructureWarning::Duplicate(chunk_type.to_owned()));
+            }
+        }
+
+        let plte = first("PLTE");
+        let idat = first("IDAT");
+
+        for (idx, &chunk_type) in types.iter().enumerate() {
+            let mut not_before = |before: &'static str, limit: Option<usize>| {
+                if limit.is_some_and(|limit| idx > limit) {
+                    warnings.push(StructureWarning::NotBefore {
+                        chunk_type: chunk_type.to_owned(),
+                        before,
+                    });
+                }
+            };
+
+            if BEFORE_PLTE.contains(&chunk_type) {
+                not_before("PLTE", plte);
+            }
+
+            if BEFORE_IDAT.contains(&chunk_type) {
+                not_before("IDAT", idat);
+            }
+
+            if AFTER_PLTE.contains(&chunk_type) && plte.is_some_and(|plte| idx < plte) {
+                warnings.push(StructureWarning::NotAfter {
+                    chunk_type: chunk_type.to_owned(),
+                    after: "PLTE",
+                });
+            }
+        }
+
+        warnings
+    }
+
+    /// Serialize the PNG like `into_bytes`, then parse the output again with strict chunk
+    /// type and crc checks, failing if it doesn't parse or breaks the ordering rules (see
+    /// `validate_structure`), e.g. to catch changes that produce subtly broken images
+    ///
+    /// The output is copied to parse it, so this briefly needs twice its size in memory.
+    /// Ordering rules are only checked for PNGs, not MNG or JNG. Files written in fast mode
+    /// have no crc (see `set_fast_mode`), so they always fail
+    pub fn into_validated_bytes(self) -> Result<Vec<u8>, PngFilesError> {
+        let signature = self.signature;
+        let bytes = self.into_bytes();
+
+        let png = Png::new_with_signature(bytes.clone(), signature)?;
+
+        if signature == PNG_HEADER {
+            let warnings = png.validate_structure();
+            if !warnings.is_empty() {
+                Err(PngFilesError::Msg(Cow::Owned(format!(
+                    "Output breaks the PNG ordering rules: {}",
+                    warnings
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                ))))?;
+            }
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::PngChunk;
+    use super::*;
+
+    // a blank image (IHDR, IDAT, IEND) with a chunk of each type inserted at its index,
+    // one after the other
+    fn with_chunks(chunks: &[(usize, &str)]) -> Png {
+        let mut png = Png::new_blank(1, 1).unwrap();
+
+        for &(idx, chunk_type) in chunks {
+            let chunk = PngChunk::new(chunk_type, vec![0; 3]).unwrap();
+            png.chunks_mut().insert(idx, chunk);
+        }
+
+        png
+    }
+
+    #[test]
+    fn well_formed_image_has_no_warnings() {
+        let mut png = with_chunks(&[(1, "gAMA"), (2, "PLTE"), (3, "bKGD")]);
+        png.insert_file("a", b"a".to_vec(), false).unwrap();
+
+        assert_eq!(png.validate_structure(), []);
+        assert!(png.into_validated_bytes().is_ok());
+    }
+
+    #[test]
+    fn split_idat_is_reported() {
+        let png = with_chunks(&[(2, "IDAT"), (2, "tEXt")]);
+
+        assert_eq!(
+            png.validate_structure(),
+            [StructureWarning::IdatNotContiguous]
+        );
+        assert!(png.into_validated_bytes().is_err());
+    }
+
+    #[test]
+    fn misplaced_chunks_are_reported() {
+        // PLTE after IDAT, and gAMA after PLTE
+        let png = with_chunks(&[(2, "PLTE"), (3, "gAMA")]);
+
+        assert_eq!(
+            png.validate_structure(),
+            [
+                StructureWarning::NotBefore {
+                    chunk_type: "PLTE".to_owned(),
+                    before: "IDAT",
+                },
+                StructureWarning::NotBefore {
+                    chunk_type: "gAMA".to_owned(),
+                    before: "PLTE",
+                },
+                StructureWarning::NotBefore {
+                    chunk_type: "gAMA".to_owned(),
+                    before: "IDAT",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates_and_missing_chunks_are_reported() {
+        let mut png = with_chunks(&[(1, "pHYs"), (1, "pHYs")]);
+        png.chunks_mut().retain(|c| c.chunk_type.as_ref() != "IDAT");
+        png.chunks_mut().pop();
+
+        assert_eq!(
+            png.validate_structure(),
+            [
+                StructureWarning::IendNotLast,
+                StructureWarning::MissingIdat,
+                StructureWarning::Duplicate("pHYs".to_owned()),
+            ]
+        );
+    }
+}
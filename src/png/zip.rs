@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::Compression;
+
+use super::{deflate, CompressionMethod, Png};
+use crate::PngFilesError;
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+// 2.0, needed for deflate
+const VERSION: u16 = 20;
+// names are utf8
+const FLAGS: u16 = 1 << 11;
+// 1980-01-01 00:00, the earliest dos date, since files carry no modification time
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    len: u32,
+    data: Vec<u8>,
+    // offset of the local header from the start of the output
+    offset: u32,
+}
+
+impl Png {
+    /// Serialize the PNG like `into_bytes`, with a ZIP archive of the embedded files
+    /// appended after IEND, making the output both a valid PNG and a valid ZIP
+    ///
+    /// Image viewers ignore anything after IEND and still show the image, while unzip and
+    /// other archive tools find the archive from the end of the file and extract each
+    /// file under its key. The files are also still embedded as usual, but `new` stops at
+    /// the archive; read the output back with `parse_all` instead, which skips it
+    ///
+    /// Fails if the archive would need ZIP64, i.e. a file or the output is 4 GiB or more,
+    /// or there are more than 65535 files
+    pub fn into_png_zip_polyglot(self) -> Result<Vec<u8>, PngFilesError> {
+        let mut entries = Vec::new();
+
+        for chunk in &self.chunks {
+            let Some(key) = chunk.chunk_type.get_key() else {
+                continue;
+            };
+
+            // a file missing from the archive would be easy to not notice, so fail instead
+            let data = self.decode_chunk(chunk)?;
+
+            let (method, stored) = match self.chunk_file(chunk)?.meta.method {
+                CompressionMethod::Store => (METHOD_STORE, data.clone()),
+                // a gzip stream's deflate data would fit, but its crc and sizes would have
+                // to be parsed out of the stream, so it's deflated again like the rest
+                CompressionMethod::Deflate
+                | CompressionMethod::Gzip
+                | CompressionMethod::Custom(_) => {
+                    let level = Compression::new(self.deflate_options.level.min(9));
+                    (METHOD_DEFLATE, deflate::deflate(&data, level, None)?)
+                }
+            };
+
+            entries.push(Entry {
+                name: key.to_owned(),
+                method,
+                crc: crc32fast::hash(&data),
+                len: u32::try_from(data.len()).map_err(|_| too_big())?,
+                data: stored,
+                offset: 0,
+            });
+        }
+
+        let mut bytes = self.into_bytes();
+
+        for entry in &mut entries {
+            entry.offset = u32::try_from(bytes.len()).map_err(|_| too_big())?;
+
+            write_local_header(&mut bytes, entry)?;
+            bytes.extend_from_slice(&entry.data);
+        }
+
+        let central_offset = u32::try_from(bytes.len()).map_err(|_| too_big())?;
+        for entry in &entries {
+            write_central_header(&mut bytes, entry)?;
+        }
+        let central_len = u32::try_from(bytes.len()).map_err(|_| too_big())? - central_offset;
+
+        let count = u16::try_from(entries.len())
+            .map_err(|_| PngFilesError::Msg(Cow::Borrowed("Too many files for a ZIP archive")))?;
+
+        bytes.write_u32::<LittleEndian>(END_OF_CENTRAL_DIRECTORY)?;
+        // this disk, disk where the central directory starts
+        bytes.write_u16::<LittleEndian>(0)?;
+        bytes.write_u16::<LittleEndian>(0)?;
+        // entries on this disk, entries in total
+        bytes.write_u16::<LittleEndian>(count)?;
+        bytes.write_u16::<LittleEndian>(count)?;
+        bytes.write_u32::<LittleEndian>(central_len)?;
+        bytes.write_u32::<LittleEndian>(central_offset)?;
+        // comment len
+        bytes.write_u16::<LittleEndian>(0)?;
+
+        Ok(bytes)
+    }
+}
+
+fn too_big() -> PngFilesError {
+    PngFilesError::Msg(Cow::Borrowed("Output too big for a ZIP archive"))
+}
+
+// fields shared by the local and central headers, from the version needed to extract up to
+// the extra field length
+fn write_common(bytes: &mut Vec<u8>, entry: &Entry) -> Result<(), PngFilesError> {
+    let compressed_len = u32::try_from(entry.data.len()).map_err(|_| too_big())?;
+    let name_len = u16::try_from(entry.name.len())
+        .map_err(|_| PngFilesError::Msg(Cow::Borrowed("Key too long for a ZIP archive")))?;
+
+    bytes.write_u16::<LittleEndian>(VERSION)?;
+    bytes.write_u16::<LittleEndian>(FLAGS)?;
+    bytes.write_u16::<LittleEndian>(entry.method)?;
+    bytes.write_u16::<LittleEndian>(DOS_TIME)?;
+    bytes.write_u16::<LittleEndian>(DOS_DATE)?;
+    bytes.write_u32::<LittleEndian>(entry.crc)?;
+    bytes.write_u32::<LittleEndian>(compressed_len)?;
+    bytes.write_u32::<LittleEndian>(entry.len)?;
+    bytes.write_u16::<LittleEndian>(name_len)?;
+    // extra field len
+    bytes.write_u16::<LittleEndian>(0)?;
+
+    Ok(())
+}
+
+fn write_local_header(bytes: &mut Vec<u8>, entry: &Entry) -> Result<(), PngFilesError> {
+    bytes.write_u32::<LittleEndian>(LOCAL_HEADER)?;
+    write_common(bytes, entry)?;
+    bytes.extend_from_slice(entry.name.as_bytes());
+
+    Ok(())
+}
+
+fn write_central_header(bytes: &mut Vec<u8>, entry: &Entry) -> Result<(), PngFilesError> {
+    bytes.write_u32::<LittleEndian>(CENTRAL_HEADER)?;
+    // version made by
+    bytes.write_u16::<LittleEndian>(VERSION)?;
+    write_common(bytes, entry)?;
+    // comment len, disk number, internal attributes, external attributes
+    bytes.write_u16::<LittleEndian>(0)?;
+    bytes.write_u16::<LittleEndian>(0)?;
+    bytes.write_u16::<LittleEndian>(0)?;
+    bytes.write_u32::<LittleEndian>(0)?;
+    bytes.write_u32::<LittleEndian>(entry.offset)?;
+    bytes.extend_from_slice(entry.name.as_bytes());
+
+    Ok(())
+}